@@ -174,13 +174,16 @@ class ChatView extends Component {
     handleExitRoom(event) {
         event.preventDefault();
 
-        // Reset the selected room and any received messages in that room.
-        this.setState({ selectedRoom: "", messages: [] });
-
         // Send the leave status to the server.
         this.state.ws.send(JSON.stringify({
             type: "leave",
+            payload: {
+                room: this.state.selectedRoom,
+            }
         }));
+
+        // Reset the selected room and any received messages in that room.
+        this.setState({ selectedRoom: "", messages: [] });
     }
 
     handleJoinRoom(roomId) {
@@ -204,14 +207,20 @@ class ChatView extends Component {
     sendStartedTyping() {
         this.state.ws.send(JSON.stringify({
             type: "typing",
-            payload: "started",
+            payload: {
+                room: this.state.selectedRoom,
+                status: "started",
+            }
         }));
     }
 
     sendStoppedTyping() {
         this.state.ws.send(JSON.stringify({
             type: "typing",
-            payload: "stopped",
+            payload: {
+                room: this.state.selectedRoom,
+                status: "stopped",
+            }
         }));
     }
 
@@ -237,11 +246,36 @@ class ChatView extends Component {
         try {
             let data = JSON.parse(event.data);
 
+            // Outputs about a room are tagged with its id. Ignore those about
+            // rooms other than the one we are in, such as a room we just left.
+            if (data.room && data.room !== this.state.selectedRoom) {
+                return;
+            }
+
             switch (data.type) {
                 case "rooms":
                     this.setState({ rooms: data.payload.rooms });
                     break;
 
+                // The room list changed after it was sent.
+                case "room-added":
+                    this.setState({
+                        rooms: [...this.state.rooms.filter(room => room.id !== data.payload.room.id), data.payload.room]
+                    });
+                    break;
+
+                case "room-updated":
+                    this.setState({
+                        rooms: this.state.rooms.map(room => room.id === data.payload.room.id ? data.payload.room : room)
+                    });
+                    break;
+
+                case "room-removed":
+                    this.setState({
+                        rooms: this.state.rooms.filter(room => room.id !== data.payload.room)
+                    });
+                    break;
+
                 // The client has successfully joined a chatroom.
                 case "joined":
                     // Update the state with the peer clients and reset any received messages.
@@ -348,7 +382,7 @@ class ChatView extends Component {
         this.state.ws.send(JSON.stringify({
             type: "post",
             payload: {
-                sender: this.state.username,
+                room: this.state.selectedRoom,
                 message: this.state.currentMessage
             }
         }));
@@ -5,19 +5,21 @@ export default class ConnectionHandler {
         this.ws = wsConn;
     }
 
-    joinServer(username) {
+    joinServer(username, room) {
         this.ws.send({
             type: "join",
             payload: {
                 username: username,
+                room: room,
             }
         })
     }
 
-    sendMessage(message) {
+    sendMessage(room, message) {
         this.ws.send({
             type: "post",
             payload: {
+                room: room,
                 message: message,
             }
         })
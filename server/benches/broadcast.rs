@@ -0,0 +1,307 @@
+//! Broadcasts a post to rooms of growing size, once by copying the serialized
+//! output for every client (as rooms used to) and once by posting in one of
+//! the server's own rooms, which share a single buffer between all clients.
+//!
+//! Every client is played by an actor that does with each output what a
+//! client's websocket actor does: write it to the connection as a frame.
+//!
+//! For every room size it reports, per broadcast, the allocations made on all
+//! threads until the last client has written the post out (in total and per
+//! client), and how long that took.
+//!
+//! Run with `cargo bench --bench broadcast`.
+
+use actix::prelude::*;
+use actix_http::ws::{OpCode, Parser};
+use actix_web::web::BytesMut;
+use bytestring::ByteString;
+use server::config::Config;
+use server::history::HistoryBackend;
+use server::lobby::Lobby;
+use server::messages::{ClientActorMessage, DroppedOutputs, FindRoom, Join, WsMessage};
+use server::proto::{MessageOutput, Output, UserOutput, UserPostedOutput};
+use server::rooms::ChatRoom;
+use std::alloc::{GlobalAlloc, Layout, System as SystemAllocator};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+// Every join is announced to everyone already in the room, so filling a room
+// sends outputs in the square of its size; much larger rooms take minutes and
+// gigabytes just to fill.
+const ROOM_SIZES: [usize; 3] = [100, 1_000, 3_000];
+const ROUNDS: u32 = 20;
+const THREADS: usize = 4;
+
+// Room for every output sent while a room fills up, so that no client is
+// disconnected for being too slow.
+const QUEUE_SIZE: usize = 1 << 16;
+
+// Counts the allocations made on every thread while counting is turned on.
+struct CountingAllocator;
+
+static COUNTING: AtomicBool = AtomicBool::new(false);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+fn count(size: usize) {
+    if COUNTING.load(Ordering::Relaxed) {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(size, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        SystemAllocator.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        SystemAllocator.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size);
+        SystemAllocator.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Strategy {
+    CopyPerClient,
+    SharedBuffer,
+}
+
+// Stands in for a client's websocket actor.
+struct Client {
+    // The connection's write buffer, which frames are written to.
+    buffer: BytesMut,
+    received: Arc<AtomicUsize>,
+    room_size: usize,
+    done: Sender<Instant>,
+}
+
+impl Actor for Client {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.set_mailbox_capacity(QUEUE_SIZE);
+    }
+}
+
+impl Handler<WsMessage> for Client {
+    type Result = ();
+
+    fn handle(&mut self, msg: WsMessage, _: &mut Context<Self>) {
+        let text = match msg {
+            WsMessage::Text(text) | WsMessage::Removed(_, text) => text,
+            WsMessage::Close(_) => panic!("client was disconnected"),
+        };
+
+        // What the websocket context does with `ctx.text(text)`: the text is
+        // written to the connection as a frame once the connection gets to it.
+        Parser::write_message(&mut self.buffer, text.as_bytes(), OpCode::Text, true, false);
+        self.buffer.clear();
+
+        let is_post =
+            text.starts_with(r#"{"type":"posted""#) || text.starts_with(r#"{"type":"user-posted""#);
+        if is_post && self.received.fetch_add(1, Ordering::AcqRel) + 1 == self.room_size {
+            let _ = self.done.send(Instant::now());
+        }
+    }
+}
+
+// Fans a post out the way rooms used to: serialized once, and then copied for
+// every client.
+struct CopyingRoom {
+    id: Uuid,
+    clients: Vec<Recipient<WsMessage>>,
+}
+
+impl Actor for CopyingRoom {
+    type Context = Context<Self>;
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Broadcast(Output);
+
+impl Handler<Broadcast> for CopyingRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: Broadcast, _: &mut Context<Self>) {
+        let text = String::from(&*msg.0.to_room_text(self.id));
+        for client in &self.clients {
+            let _ = client.try_send(WsMessage::Text(ByteString::from(text.to_owned())));
+        }
+    }
+}
+
+struct Measurement {
+    allocations: usize,
+    allocated_bytes: usize,
+    latency: Duration,
+}
+
+fn body() -> String {
+    "The quick brown fox jumps over the lazy dog. ".repeat(6)
+}
+
+fn broadcast(
+    strategy: Strategy,
+    room: &Addr<ChatRoom>,
+    copying_room: &Addr<CopyingRoom>,
+    poster: Uuid,
+    received: &AtomicUsize,
+    done: &Receiver<Instant>,
+) -> Measurement {
+    received.store(0, Ordering::Release);
+
+    ALLOCATIONS.store(0, Ordering::Relaxed);
+    ALLOCATED_BYTES.store(0, Ordering::Relaxed);
+    COUNTING.store(true, Ordering::Relaxed);
+    let start = Instant::now();
+
+    match strategy {
+        Strategy::CopyPerClient => {
+            let user = UserOutput::new(poster, "client0");
+            let message = MessageOutput::new(1, user, &body(), chrono::Utc::now());
+            copying_room.do_send(Broadcast(Output::UserPosted(UserPostedOutput::new(
+                message,
+            ))));
+        }
+        Strategy::SharedBuffer => room.do_send(ClientActorMessage {
+            id: poster,
+            msg: body(),
+            nonce: None,
+        }),
+    }
+    let finished = done.recv().unwrap();
+
+    COUNTING.store(false, Ordering::Relaxed);
+
+    Measurement {
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        allocated_bytes: ALLOCATED_BYTES.load(Ordering::Relaxed),
+        latency: finished - start,
+    }
+}
+
+async fn run(room_size: usize, lobby: &Addr<Lobby>, room_arbiter: &Arbiter, arbiters: &[Arbiter]) {
+    let received = Arc::new(AtomicUsize::new(0));
+    let (done_tx, done_rx) = mpsc::channel();
+
+    let clients: Vec<(Uuid, Recipient<WsMessage>)> = (0..room_size)
+        .map(|i| {
+            let received = Arc::clone(&received);
+            let done = done_tx.clone();
+            let arbiter = arbiters[i % arbiters.len()].handle();
+            let client = Client::start_in_arbiter(&arbiter, move |_| Client {
+                buffer: BytesMut::with_capacity(4096),
+                received,
+                room_size,
+                done,
+            });
+            (Uuid::new_v4(), client.recipient())
+        })
+        .collect();
+
+    // The first client creates the room by joining it, and posts in it.
+    let room_id = Uuid::new_v4();
+    let (poster, poster_addr) = &clients[0];
+    let room = lobby
+        .send(FindRoom {
+            addr: poster_addr.clone(),
+            room_id,
+            self_id: *poster,
+            username: "client0".to_string(),
+            max_clients: Some(room_size),
+        })
+        .await
+        .unwrap()
+        .expect("room wasn't created");
+
+    for (i, (id, addr)) in clients.iter().enumerate() {
+        let joined = room
+            .send(Join {
+                addr: addr.clone(),
+                self_id: *id,
+                username: format!("client{}", i),
+                wait: false,
+                ip: None,
+                password: None,
+                invite: None,
+                dropped: DroppedOutputs::default(),
+            })
+            .await
+            .unwrap();
+        assert!(joined, "client {} couldn't join", i);
+    }
+
+    // Like a real room, it has a thread of its own.
+    let clients = clients.iter().map(|(_, addr)| addr.clone()).collect();
+    let copying_room =
+        CopyingRoom::start_in_arbiter(&room_arbiter.handle(), move |_| CopyingRoom {
+            id: room_id,
+            clients,
+        });
+
+    for &(strategy, name) in &[
+        (Strategy::CopyPerClient, "copy per client"),
+        (Strategy::SharedBuffer, "shared buffer"),
+    ] {
+        // Warm up, which also waits for everyone to hear about everyone
+        // joining.
+        broadcast(strategy, &room, &copying_room, *poster, &received, &done_rx);
+
+        let mut allocations = 0;
+        let mut allocated_bytes = 0;
+        let mut latency = Duration::default();
+        for _ in 0..ROUNDS {
+            let m = broadcast(strategy, &room, &copying_room, *poster, &received, &done_rx);
+            allocations += m.allocations;
+            allocated_bytes += m.allocated_bytes;
+            latency += m.latency;
+        }
+
+        let allocations = allocations / ROUNDS as usize;
+        let allocated_bytes = allocated_bytes / ROUNDS as usize;
+        println!(
+            "{:>6} clients  {:<16} {:>7} allocs ({:>4.1} per client) {:>9} bytes ({:>5} per client)  latency {:>10.1?}",
+            room_size,
+            name,
+            allocations,
+            allocations as f64 / room_size as f64,
+            allocated_bytes,
+            allocated_bytes / room_size,
+            latency / ROUNDS,
+        );
+    }
+}
+
+fn main() {
+    System::new().block_on(async {
+        let mut config = Config::default();
+        config.rooms.clear();
+        config.room_threads = Some(1);
+        config.limits.outbound_queue_size = QUEUE_SIZE;
+
+        let lobby = Lobby::new(HistoryBackend::Memory(Default::default()), &config)
+            .unwrap()
+            .start();
+        let room_arbiter = Arbiter::new();
+        let arbiters: Vec<Arbiter> = (0..THREADS).map(|_| Arbiter::new()).collect();
+
+        for &room_size in &ROOM_SIZES {
+            run(room_size, &lobby, &room_arbiter, &arbiters).await;
+        }
+
+        System::current().stop();
+    });
+}
@@ -0,0 +1,222 @@
+use actix_web::{http::header, web::Query, HttpRequest};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::fmt;
+use uuid::Uuid;
+
+// Namespace for turning token subjects that aren't uuids into stable user ids.
+const SUBJECT_NAMESPACE: Uuid = Uuid::from_u128(0x3f0c_5b2e_81a4_4d6f_9e27_c4b1_d05a_7e93);
+
+/// How clients are authenticated when they open a WebSocket connection.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AuthConfig {
+    /// Key that tokens are signed with (HMAC-SHA256). Without a key no tokens
+    /// are accepted.
+    pub secret: Option<String>,
+
+    /// Whether clients without a token may connect, picking any username.
+    pub allow_anonymous: bool,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            secret: None,
+            allow_anonymous: true,
+        }
+    }
+}
+
+/// Who a verified token says the client is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub id: Uuid,
+    pub name: String,
+}
+
+/// Reason a connection was refused.
+#[derive(Debug)]
+pub enum AuthError {
+    /// No token was given and anonymous access is turned off.
+    Missing,
+    /// The token could not be verified.
+    Invalid(jsonwebtoken::errors::Error),
+    /// A token was given but the server has no key to verify it with.
+    NotConfigured,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "missing token"),
+            AuthError::Invalid(e) => write!(f, "invalid token: {}", e),
+            AuthError::NotConfigured => write!(f, "authentication is not configured"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+// Finds the token of a WebSocket upgrade request, either in a `token` query
+// parameter or as a bearer token in the `Authorization` header. Browsers can't
+// set headers on WebSocket requests, hence the query parameter.
+fn find_token(req: &HttpRequest) -> Option<String> {
+    let from_query = Query::<TokenQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.into_inner().token);
+
+    from_query.or_else(|| {
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string())
+    })
+}
+
+impl AuthConfig {
+    /// Authenticates a WebSocket upgrade request. Returns the identity of the
+    /// client, or `None` for an anonymous client if those are allowed.
+    pub fn authenticate(&self, req: &HttpRequest) -> Result<Option<Identity>, AuthError> {
+        let token = match find_token(req) {
+            Some(token) => token,
+            None if self.allow_anonymous => return Ok(None),
+            None => return Err(AuthError::Missing),
+        };
+
+        let secret = self.secret.as_ref().ok_or(AuthError::NotConfigured)?;
+
+        let claims = decode::<Claims>(
+            &token,
+            &DecodingKey::from_secret(secret.as_bytes()),
+            &Validation::new(Algorithm::HS256),
+        )
+        .map_err(AuthError::Invalid)?
+        .claims;
+
+        // The subject is the user's stable id. Subjects that aren't uuids are
+        // mapped to one.
+        let id = Uuid::parse_str(&claims.sub)
+            .unwrap_or_else(|_| Uuid::new_v5(&SUBJECT_NAMESPACE, claims.sub.as_bytes()));
+        let name = claims.name.unwrap_or(claims.sub);
+
+        Ok(Some(Identity { id, name }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde::Serialize;
+
+    const SECRET: &str = "sekrit";
+
+    #[derive(Serialize)]
+    struct TestClaims<'a> {
+        sub: &'a str,
+        name: Option<&'a str>,
+        exp: i64,
+    }
+
+    fn token(sub: &str, name: Option<&str>, expires_in: i64, secret: &str) -> String {
+        let claims = TestClaims {
+            sub,
+            name,
+            exp: chrono::Utc::now().timestamp() + expires_in,
+        };
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    fn config(allow_anonymous: bool) -> AuthConfig {
+        AuthConfig {
+            secret: Some(SECRET.to_string()),
+            allow_anonymous,
+        }
+    }
+
+    fn with_query(token: &str) -> HttpRequest {
+        TestRequest::with_uri(&format!("/ws/?token={}", token)).to_http_request()
+    }
+
+    #[test]
+    fn accepts_a_token_from_the_query() {
+        let id = Uuid::new_v4().to_string();
+        let req = with_query(&token(&id, Some("Joel"), 3600, SECRET));
+
+        let identity = config(false).authenticate(&req).unwrap().unwrap();
+        assert_eq!(identity.id.to_string(), id);
+        assert_eq!(identity.name, "Joel");
+    }
+
+    #[test]
+    fn accepts_a_bearer_token() {
+        let id = Uuid::new_v4().to_string();
+        let req = TestRequest::with_uri("/ws/")
+            .insert_header((
+                header::AUTHORIZATION,
+                format!("Bearer {}", token(&id, Some("Joel"), 3600, SECRET)),
+            ))
+            .to_http_request();
+
+        let identity = config(false).authenticate(&req).unwrap().unwrap();
+        assert_eq!(identity.id.to_string(), id);
+    }
+
+    #[test]
+    fn refuses_an_expired_token() {
+        let req = with_query(&token("joel", None, -3600, SECRET));
+        assert!(matches!(
+            config(true).authenticate(&req),
+            Err(AuthError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn refuses_a_token_signed_with_another_key() {
+        let req = with_query(&token("joel", None, 3600, "not the secret"));
+        assert!(matches!(
+            config(true).authenticate(&req),
+            Err(AuthError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn requires_a_token_without_anonymous_access() {
+        let req = TestRequest::with_uri("/ws/").to_http_request();
+        assert!(matches!(
+            config(false).authenticate(&req),
+            Err(AuthError::Missing)
+        ));
+        assert_eq!(config(true).authenticate(&req).unwrap(), None);
+    }
+
+    #[test]
+    fn maps_subjects_that_are_not_uuids_to_stable_ids() {
+        let req = with_query(&token("joel", None, 3600, SECRET));
+        let identity = config(false).authenticate(&req).unwrap().unwrap();
+        assert_eq!(identity.id, Uuid::new_v5(&SUBJECT_NAMESPACE, b"joel"));
+        // Without a name the subject is used.
+        assert_eq!(identity.name, "joel");
+
+        let other = with_query(&token("joel", Some("Joel"), 60, SECRET));
+        let again = config(false).authenticate(&other).unwrap().unwrap();
+        assert_eq!(again.id, identity.id);
+    }
+}
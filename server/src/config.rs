@@ -0,0 +1,779 @@
+use crate::auth::AuthConfig;
+use crate::credentials::is_valid_password_hash;
+use crate::proto::{Access, Lifecycle, Role};
+use crate::rate_limit::RateLimitConfig;
+use crate::validation::{is_valid_room_name, is_valid_room_topic};
+use clap::Parser;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{fs, io, thread};
+use uuid::Uuid;
+
+// Bytes a post input takes besides its message: the type, room id and nonce,
+// and the JSON around them.
+const POST_ENVELOPE_SIZE: usize = 1024;
+
+/// Configuration of the server.
+///
+/// Settings are read from a TOML file (see `chat.example.toml`), then
+/// overridden by environment variables and finally by command-line flags.
+/// Anything left unset keeps its default.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Address the server listens on.
+    pub bind: SocketAddr,
+
+    /// Number of threads the rooms are spread over, one per CPU core if not
+    /// set.
+    pub room_threads: Option<usize>,
+    pub timeouts: TimeoutConfig,
+    pub limits: LimitConfig,
+    pub storage: StorageConfig,
+    pub auth: AuthConfig,
+    pub rate_limits: RateLimitConfig,
+
+    /// Whether joining a room that doesn't exist creates it, rather than
+    /// failing.
+    pub create_rooms_on_join: bool,
+
+    /// Rooms that exist when the server starts.
+    pub rooms: Vec<RoomConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TimeoutConfig {
+    /// How often heartbeat pings are sent (seconds).
+    #[serde(deserialize_with = "seconds")]
+    pub heartbeat_interval: Duration,
+
+    /// How long before lack of client response causes a timeout (seconds).
+    #[serde(deserialize_with = "seconds")]
+    pub client_timeout: Duration,
+
+    /// How long the seat of a client that lost its connection is held for it
+    /// to resume the session (seconds).
+    #[serde(deserialize_with = "seconds")]
+    pub resume_grace_period: Duration,
+
+    /// How long a nonce that a message was posted with is remembered, for
+    /// retries of the post to be recognized (seconds).
+    #[serde(deserialize_with = "seconds")]
+    pub nonce_window: Duration,
+
+    /// How long an ephemeral room is empty before it is deleted (seconds).
+    #[serde(deserialize_with = "seconds")]
+    pub idle_room_timeout: Duration,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LimitConfig {
+    /// Capacity of rooms that are created without an explicit capacity.
+    pub default_max_clients: usize,
+
+    /// How many of the most recent messages are sent to a client joining a
+    /// room.
+    pub joined_history_limit: usize,
+
+    /// Largest page of history a client can ask for at once.
+    pub max_history_page_size: usize,
+
+    /// Longest message body a client can post, in characters.
+    pub max_message_length: usize,
+
+    /// Largest WebSocket frame a client can send, in bytes. Clients sending
+    /// larger frames are disconnected. Must fit a post of the longest message,
+    /// at up to 4 bytes per character.
+    pub max_frame_size: usize,
+
+    /// How many outputs can be queued for a client before it is considered
+    /// too slow. Typing events are left out for a client with a full queue,
+    /// which is told how many on the next heartbeat. Anything else disconnects
+    /// it.
+    pub outbound_queue_size: usize,
+
+    /// How many persistent rooms a user can have created at once. Only
+    /// authenticated users can create persistent rooms, 0 keeps everyone from
+    /// creating them.
+    pub max_persistent_rooms: usize,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct StorageConfig {
+    /// SQLite database the chat history is kept in. History is kept in memory
+    /// (and lost on restart) if this isn't set.
+    pub history_db: Option<PathBuf>,
+}
+
+/// A room that exists when the server starts. Rooms that clients create are
+/// started from one as well.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RoomConfig {
+    /// Fixed id so that the room's history can be found again after a
+    /// restart.
+    pub id: Uuid,
+    pub name: String,
+
+    #[serde(default)]
+    pub topic: Option<String>,
+
+    /// Capacity of the room, `limits.default-max-clients` if not set.
+    pub max_clients: Option<usize>,
+
+    /// Users who own the room.
+    #[serde(default)]
+    pub owners: Vec<Uuid>,
+
+    /// Users who may moderate the room.
+    #[serde(default)]
+    pub moderators: Vec<Uuid>,
+
+    /// Users who may post in a room whose default role is `guest`.
+    #[serde(default)]
+    pub members: Vec<Uuid>,
+
+    /// Users who may only read along in a room whose default role is
+    /// `member`.
+    #[serde(default)]
+    pub guests: Vec<Uuid>,
+
+    /// Role of everyone else, `guest` for a room that only its owners and
+    /// moderators can post in.
+    #[serde(default)]
+    pub default_role: Role,
+
+    /// Whether the room is deleted after being empty for a while.
+    #[serde(default = "persistent")]
+    pub lifecycle: Lifecycle,
+
+    /// Argon2 hash (PHC string) of the password needed to join the room.
+    #[serde(default)]
+    pub password_hash: Option<String>,
+
+    /// Whether the room is left out of the list of rooms.
+    #[serde(default)]
+    pub private: bool,
+
+    /// Whether joining the room takes an invite.
+    #[serde(default)]
+    pub invite_only: bool,
+}
+
+// Rooms from the configuration are kept unless they are configured otherwise.
+fn persistent() -> Lifecycle {
+    Lifecycle::Persistent
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: SocketAddr::from(([0, 0, 0, 0], 8080)),
+            room_threads: None,
+            timeouts: TimeoutConfig::default(),
+            limits: LimitConfig::default(),
+            storage: StorageConfig::default(),
+            auth: AuthConfig::default(),
+            rate_limits: RateLimitConfig::default(),
+            create_rooms_on_join: true,
+            rooms: vec![
+                RoomConfig {
+                    id: Uuid::from_u128(0x6c1d_7a4e_2f0b_4c1e_9b5a_3d8e_0f21_a001),
+                    name: "Default room".to_string(),
+                    topic: None,
+                    max_clients: None,
+                    owners: Vec::new(),
+                    moderators: Vec::new(),
+                    members: Vec::new(),
+                    guests: Vec::new(),
+                    default_role: Role::Member,
+                    lifecycle: Lifecycle::Persistent,
+                    password_hash: None,
+                    private: false,
+                    invite_only: false,
+                },
+                RoomConfig {
+                    id: Uuid::from_u128(0x6c1d_7a4e_2f0b_4c1e_9b5a_3d8e_0f21_a002),
+                    name: "Joel's room".to_string(),
+                    topic: None,
+                    max_clients: None,
+                    owners: Vec::new(),
+                    moderators: Vec::new(),
+                    members: Vec::new(),
+                    guests: Vec::new(),
+                    default_role: Role::Member,
+                    lifecycle: Lifecycle::Persistent,
+                    password_hash: None,
+                    private: false,
+                    invite_only: false,
+                },
+            ],
+        }
+    }
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        TimeoutConfig {
+            heartbeat_interval: Duration::from_secs(5),
+            client_timeout: Duration::from_secs(10),
+            resume_grace_period: Duration::from_secs(60),
+            nonce_window: Duration::from_secs(300),
+            idle_room_timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+impl Default for LimitConfig {
+    fn default() -> Self {
+        LimitConfig {
+            default_max_clients: 10,
+            joined_history_limit: 50,
+            max_history_page_size: 100,
+            max_message_length: 2000,
+            max_frame_size: 64 * 1024,
+            outbound_queue_size: 64,
+            max_persistent_rooms: 5,
+        }
+    }
+}
+
+impl RoomConfig {
+    /// Names of the settings of a room, as they are written.
+    pub const FIELDS: &'static [&'static str] = &[
+        "id",
+        "name",
+        "topic",
+        "max-clients",
+        "owners",
+        "moderators",
+        "members",
+        "guests",
+        "default-role",
+        "lifecycle",
+        "password-hash",
+        "private",
+        "invite-only",
+    ];
+
+    /// Capacity of the room.
+    pub fn max_clients(&self, limits: &LimitConfig) -> usize {
+        self.max_clients.unwrap_or(limits.default_max_clients)
+    }
+
+    /// Roles of the users listed in the room. Users listed more than once get
+    /// the highest of their roles.
+    pub fn roles(&self) -> HashMap<Uuid, Role> {
+        let guests = self.guests.iter().map(|id| (*id, Role::Guest));
+        let members = self.members.iter().map(|id| (*id, Role::Member));
+        let moderators = self.moderators.iter().map(|id| (*id, Role::Moderator));
+        let owners = self.owners.iter().map(|id| (*id, Role::Owner));
+        guests
+            .chain(members)
+            .chain(moderators)
+            .chain(owners)
+            .collect()
+    }
+
+    /// Who can find and join the room.
+    pub fn access(&self) -> Access {
+        Access {
+            private: self.private,
+            password: self.password_hash.is_some(),
+            invite_only: self.invite_only,
+        }
+    }
+}
+
+pub fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+/// Command-line flags. Every flag can also be set with the environment
+/// variable next to it.
+#[derive(Debug, Parser)]
+#[command(about = "WebSocket chat server")]
+struct Args {
+    /// TOML file to read the configuration from
+    #[arg(long, env = "CHAT_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Address to listen on
+    #[arg(long, env = "CHAT_BIND")]
+    bind: Option<SocketAddr>,
+
+    /// Number of threads to spread the rooms over
+    #[arg(long, env = "CHAT_ROOM_THREADS")]
+    room_threads: Option<usize>,
+
+    /// Seconds between heartbeat pings
+    #[arg(long, env = "CHAT_HEARTBEAT_INTERVAL")]
+    heartbeat_interval: Option<u64>,
+
+    /// Seconds without a response before a client times out
+    #[arg(long, env = "CHAT_CLIENT_TIMEOUT")]
+    client_timeout: Option<u64>,
+
+    /// Seconds a disconnected client's seat is held for it to resume
+    #[arg(long, env = "CHAT_RESUME_GRACE_PERIOD")]
+    resume_grace_period: Option<u64>,
+
+    /// Seconds a post's nonce is remembered to recognize retries
+    #[arg(long, env = "CHAT_NONCE_WINDOW")]
+    nonce_window: Option<u64>,
+
+    /// Seconds an ephemeral room is empty before it is deleted
+    #[arg(long, env = "CHAT_IDLE_ROOM_TIMEOUT")]
+    idle_room_timeout: Option<u64>,
+
+    /// Capacity of rooms created without an explicit capacity
+    #[arg(long, env = "CHAT_DEFAULT_MAX_CLIENTS")]
+    default_max_clients: Option<usize>,
+
+    /// Number of recent messages sent to a client joining a room
+    #[arg(long, env = "CHAT_JOINED_HISTORY_LIMIT")]
+    joined_history_limit: Option<usize>,
+
+    /// Largest page of history a client can ask for
+    #[arg(long, env = "CHAT_MAX_HISTORY_PAGE_SIZE")]
+    max_history_page_size: Option<usize>,
+
+    /// Longest message body a client can post, in characters
+    #[arg(long, env = "CHAT_MAX_MESSAGE_LENGTH")]
+    max_message_length: Option<usize>,
+
+    /// Largest WebSocket frame a client can send, in bytes
+    #[arg(long, env = "CHAT_MAX_FRAME_SIZE")]
+    max_frame_size: Option<usize>,
+
+    /// Number of outputs queued for a client before it is considered too slow
+    #[arg(long, env = "CHAT_OUTBOUND_QUEUE_SIZE")]
+    outbound_queue_size: Option<usize>,
+
+    /// Number of persistent rooms a user can have created at once
+    #[arg(long, env = "CHAT_MAX_PERSISTENT_ROOMS")]
+    max_persistent_rooms: Option<usize>,
+
+    /// SQLite database to keep the chat history in
+    #[arg(long, env = "CHAT_HISTORY_DB")]
+    history_db: Option<PathBuf>,
+
+    /// Key that authentication tokens are signed with
+    #[arg(long, env = "CHAT_AUTH_SECRET", hide_env_values = true)]
+    auth_secret: Option<String>,
+
+    /// Whether clients without a token may connect
+    #[arg(long, env = "CHAT_ALLOW_ANONYMOUS")]
+    allow_anonymous: Option<bool>,
+
+    /// Whether joining a room that doesn't exist creates it
+    #[arg(long, env = "CHAT_CREATE_ROOMS_ON_JOIN")]
+    create_rooms_on_join: Option<bool>,
+}
+
+/// Reason the configuration could not be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "could not parse {}: {}", path.display(), e),
+            ConfigError::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Loads the configuration from the file, environment and command line.
+    /// Exits the process if the command line can't be parsed.
+    pub fn load() -> Result<Self, ConfigError> {
+        let args = Args::parse();
+
+        let mut config = match &args.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+
+        config.apply(args);
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Number of threads the rooms are spread over.
+    pub fn room_threads(&self) -> usize {
+        self.room_threads
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, NonZeroUsize::get))
+    }
+
+    fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_owned(), e))
+    }
+
+    // Overrides the settings that were given as flags or environment
+    // variables.
+    fn apply(&mut self, args: Args) {
+        if let Some(bind) = args.bind {
+            self.bind = bind;
+        }
+        if let Some(threads) = args.room_threads {
+            self.room_threads = Some(threads);
+        }
+        if let Some(secs) = args.heartbeat_interval {
+            self.timeouts.heartbeat_interval = Duration::from_secs(secs);
+        }
+        if let Some(secs) = args.client_timeout {
+            self.timeouts.client_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = args.resume_grace_period {
+            self.timeouts.resume_grace_period = Duration::from_secs(secs);
+        }
+        if let Some(secs) = args.nonce_window {
+            self.timeouts.nonce_window = Duration::from_secs(secs);
+        }
+        if let Some(secs) = args.idle_room_timeout {
+            self.timeouts.idle_room_timeout = Duration::from_secs(secs);
+        }
+        if let Some(max_clients) = args.default_max_clients {
+            self.limits.default_max_clients = max_clients;
+        }
+        if let Some(limit) = args.joined_history_limit {
+            self.limits.joined_history_limit = limit;
+        }
+        if let Some(size) = args.max_history_page_size {
+            self.limits.max_history_page_size = size;
+        }
+        if let Some(length) = args.max_message_length {
+            self.limits.max_message_length = length;
+        }
+        if let Some(size) = args.max_frame_size {
+            self.limits.max_frame_size = size;
+        }
+        if let Some(size) = args.outbound_queue_size {
+            self.limits.outbound_queue_size = size;
+        }
+        if let Some(count) = args.max_persistent_rooms {
+            self.limits.max_persistent_rooms = count;
+        }
+        if let Some(path) = args.history_db {
+            self.storage.history_db = Some(path);
+        }
+        if let Some(secret) = args.auth_secret {
+            self.auth.secret = Some(secret);
+        }
+        if let Some(allow_anonymous) = args.allow_anonymous {
+            self.auth.allow_anonymous = allow_anonymous;
+        }
+        if let Some(create) = args.create_rooms_on_join {
+            self.create_rooms_on_join = create;
+        }
+    }
+
+    // Checks the settings make sense together, so that mistakes are reported
+    // when the server starts rather than showing up as odd behaviour later.
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: &str| Err(ConfigError::Invalid(reason.to_string()));
+
+        if self.room_threads == Some(0) {
+            return invalid("room-threads must be at least 1");
+        }
+
+        let timeouts = &self.timeouts;
+        if timeouts.heartbeat_interval.as_secs() == 0 {
+            return invalid("timeouts.heartbeat-interval must be at least 1 second");
+        }
+        if timeouts.idle_room_timeout.as_secs() == 0 {
+            return invalid("timeouts.idle-room-timeout must be at least 1 second");
+        }
+        if timeouts.client_timeout <= timeouts.heartbeat_interval {
+            return invalid(
+                "timeouts.client-timeout must be longer than timeouts.heartbeat-interval",
+            );
+        }
+
+        let limits = &self.limits;
+        if limits.default_max_clients == 0 {
+            return invalid("limits.default-max-clients must be at least 1");
+        }
+        // Clients page back from the oldest message they were sent on joining,
+        // so they have to be sent at least one.
+        if limits.joined_history_limit == 0 {
+            return invalid("limits.joined-history-limit must be at least 1");
+        }
+        if limits.max_history_page_size == 0 {
+            return invalid("limits.max-history-page-size must be at least 1");
+        }
+        if limits.max_message_length == 0 {
+            return invalid("limits.max-message-length must be at least 1");
+        }
+        // A frame has to be able to carry a post of the longest message, whose
+        // characters take up to 4 bytes each.
+        let longest_post = limits
+            .max_message_length
+            .saturating_mul(4)
+            .saturating_add(POST_ENVELOPE_SIZE);
+        if limits.max_frame_size < longest_post {
+            return invalid(&format!(
+                "limits.max-frame-size must be at least {} bytes to fit a post of \
+                 limits.max-message-length characters",
+                longest_post
+            ));
+        }
+        if limits.outbound_queue_size == 0 {
+            return invalid("limits.outbound-queue-size must be at least 1");
+        }
+
+        if self.auth.secret.as_deref() == Some("") {
+            return invalid("auth.secret must not be empty");
+        }
+        if !self.auth.allow_anonymous && self.auth.secret.is_none() {
+            return invalid("auth.secret must be set when anonymous access is turned off");
+        }
+
+        for (name, rate) in self.rate_limits.rates() {
+            if rate.burst == 0 {
+                return Err(ConfigError::Invalid(format!(
+                    "rate-limits.{}.burst must be at least 1",
+                    name
+                )));
+            }
+            if !(rate.per_second.is_finite() && rate.per_second > 0.0) {
+                return Err(ConfigError::Invalid(format!(
+                    "rate-limits.{}.per-second must be a positive number",
+                    name
+                )));
+            }
+        }
+
+        let mut room_ids = HashSet::new();
+        for room in &self.rooms {
+            if !room_ids.insert(room.id) {
+                return Err(ConfigError::Invalid(format!(
+                    "room id {} is used more than once",
+                    room.id
+                )));
+            }
+            if !is_valid_room_name(&room.name) {
+                return Err(ConfigError::Invalid(format!(
+                    "room {} has an invalid name {:?}",
+                    room.id, room.name
+                )));
+            }
+            if room
+                .topic
+                .as_deref()
+                .is_some_and(|topic| !is_valid_room_topic(topic))
+            {
+                return Err(ConfigError::Invalid(format!(
+                    "room {} has an invalid topic {:?}",
+                    room.id, room.topic
+                )));
+            }
+            if room
+                .password_hash
+                .as_deref()
+                .is_some_and(|hash| !is_valid_password_hash(hash))
+            {
+                return Err(ConfigError::Invalid(format!(
+                    "room {} has an invalid password-hash",
+                    room.id
+                )));
+            }
+            if room.max_clients == Some(0) {
+                return Err(ConfigError::Invalid(format!(
+                    "room {} must have a max-clients of at least 1",
+                    room.id
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns why the configuration is invalid.
+    fn reason(config: &Config) -> String {
+        match config.validate() {
+            Err(ConfigError::Invalid(reason)) => reason,
+            other => panic!("expected the configuration to be invalid, got {:?}", other),
+        }
+    }
+
+    fn parse(text: &str) -> Config {
+        toml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn default_is_valid() {
+        Config::default().validate().unwrap();
+    }
+
+    #[test]
+    fn example_is_valid() {
+        parse(include_str!("../chat.example.toml"))
+            .validate()
+            .unwrap();
+    }
+
+    #[test]
+    fn timeouts_must_make_sense() {
+        let mut config = Config::default();
+        config.timeouts.heartbeat_interval = Duration::from_secs(10);
+        config.timeouts.client_timeout = Duration::from_secs(10);
+        assert_eq!(
+            reason(&config),
+            "timeouts.client-timeout must be longer than timeouts.heartbeat-interval"
+        );
+
+        let mut config = Config::default();
+        config.timeouts.heartbeat_interval = Duration::from_millis(500);
+        assert_eq!(
+            reason(&config),
+            "timeouts.heartbeat-interval must be at least 1 second"
+        );
+    }
+
+    #[test]
+    fn limits_must_be_at_least_one() {
+        let config = Config {
+            room_threads: Some(0),
+            ..Config::default()
+        };
+        assert_eq!(reason(&config), "room-threads must be at least 1");
+
+        let mut config = Config::default();
+        config.limits.joined_history_limit = 0;
+        assert_eq!(
+            reason(&config),
+            "limits.joined-history-limit must be at least 1"
+        );
+
+        let mut config = Config::default();
+        config.limits.outbound_queue_size = 0;
+        assert_eq!(
+            reason(&config),
+            "limits.outbound-queue-size must be at least 1"
+        );
+    }
+
+    #[test]
+    fn frames_must_fit_the_longest_message() {
+        let mut config = Config::default();
+        config.limits.max_message_length = 100;
+        config.limits.max_frame_size = 4 * 100 + POST_ENVELOPE_SIZE;
+        assert!(config.validate().is_ok());
+
+        // One byte short of a post of 100 four-byte characters.
+        config.limits.max_frame_size -= 1;
+        assert_eq!(
+            reason(&config),
+            "limits.max-frame-size must be at least 1424 bytes to fit a post of \
+             limits.max-message-length characters"
+        );
+    }
+
+    #[test]
+    fn auth_needs_a_secret_without_anonymous_access() {
+        let mut config = Config::default();
+        config.auth.allow_anonymous = false;
+        assert_eq!(
+            reason(&config),
+            "auth.secret must be set when anonymous access is turned off"
+        );
+
+        config.auth.secret = Some(String::new());
+        assert_eq!(reason(&config), "auth.secret must not be empty");
+
+        config.auth.secret = Some("secret".to_string());
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn rates_must_be_positive() {
+        let config = parse(
+            "[rate-limits.post]
+            connection = { burst = 0, per-second = 1.0 }
+            ip = { burst = 1, per-second = 1.0 }",
+        );
+        assert_eq!(
+            reason(&config),
+            "rate-limits.post.connection.burst must be at least 1"
+        );
+
+        let config = parse("[rate-limits]\nstrikes = { burst = 1, per-second = 0.0 }");
+        assert_eq!(
+            reason(&config),
+            "rate-limits.strikes.per-second must be a positive number"
+        );
+    }
+
+    #[test]
+    fn every_room_setting_is_listed() {
+        let room = serde_json::to_value(&Config::default().rooms[0]).unwrap();
+        let mut fields: Vec<&str> = room
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        fields.sort_unstable();
+
+        let mut listed = RoomConfig::FIELDS.to_vec();
+        listed.sort_unstable();
+        assert_eq!(fields, listed);
+    }
+
+    #[test]
+    fn rooms_must_be_valid() {
+        let room = Config::default().rooms[0].clone();
+
+        let mut config = Config::default();
+        config.rooms.push(room.clone());
+        assert_eq!(
+            reason(&config),
+            format!("room id {} is used more than once", room.id)
+        );
+
+        let mut config = Config::default();
+        config.rooms[0].name = " Default room".to_string();
+        assert_eq!(
+            reason(&config),
+            format!("room {} has an invalid name \" Default room\"", room.id)
+        );
+
+        let mut config = Config::default();
+        config.rooms[0].password_hash = Some("hunter2".to_string());
+        assert_eq!(
+            reason(&config),
+            format!("room {} has an invalid password-hash", room.id)
+        );
+
+        let mut config = Config::default();
+        config.rooms[0].max_clients = Some(0);
+        assert_eq!(
+            reason(&config),
+            format!("room {} must have a max-clients of at least 1", room.id)
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use uuid::Uuid;
+
+/// Hashes a room password with Argon2id and a random salt. The hash is a PHC
+/// string, which holds the parameters and salt needed to verify it.
+pub fn hash_password(password: &str) -> Option<String> {
+    // A v4 UUID is 122 random bits from the OS, plenty for a salt.
+    let salt = match SaltString::encode_b64(Uuid::new_v4().as_bytes()) {
+        Ok(salt) => salt,
+        Err(e) => {
+            println!("Failed to salt a room password: {}", e);
+            return None;
+        }
+    };
+    match Argon2::default().hash_password(password.as_bytes(), &salt) {
+        Ok(hash) => Some(hash.to_string()),
+        Err(e) => {
+            println!("Failed to hash a room password: {}", e);
+            None
+        }
+    }
+}
+
+/// Returns true if the password matches the hash.
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// Returns true if the hash is a PHC string that passwords can be checked
+/// against.
+pub fn is_valid_password_hash(hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok()
+}
+
+/// Returns a new invite code that can't be guessed.
+pub fn new_invite_code() -> String {
+    Uuid::new_v4().to_simple().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_match_their_hash() {
+        let hash = hash_password("hunter2").unwrap();
+        assert!(is_valid_password_hash(&hash));
+        assert!(verify_password("hunter2", &hash));
+    }
+
+    #[test]
+    fn other_passwords_do_not_match() {
+        let hash = hash_password("hunter2").unwrap();
+        assert!(!verify_password("hunter3", &hash));
+        assert!(!verify_password("", &hash));
+    }
+
+    #[test]
+    fn hashes_are_salted() {
+        assert_ne!(hash_password("hunter2"), hash_password("hunter2"));
+    }
+
+    #[test]
+    fn malformed_hashes_match_nothing() {
+        for hash in &["", "hunter2", "$argon2id$v=19$m=19456,t=2,p=1$not a salt"] {
+            assert!(!is_valid_password_hash(hash));
+            assert!(!verify_password("hunter2", hash));
+        }
+    }
+
+    #[test]
+    fn invite_codes_are_unique() {
+        let code = new_invite_code();
+        assert_eq!(code.len(), 32);
+        assert!(code.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(code, new_invite_code());
+    }
+}
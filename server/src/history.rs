@@ -0,0 +1,516 @@
+mod sqlite;
+
+pub use sqlite::SqliteHistoryStore;
+
+use crate::config::RoomConfig;
+use crate::proto::{MessageId, MessageOutput, UserOutput};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use uuid::Uuid;
+
+/// Storage for the chat history of every room and direct conversation. Rooms
+/// append to the store as messages are posted and read from it when clients
+/// join them, the lobby does the same for direct messages.
+///
+/// The store assigns every message an id that is unique within its
+/// conversation and higher than the id of every message posted before it,
+/// starting at 1.
+pub trait HistoryStore {
+    /// Appends a new message to the end of a conversation's history and
+    /// returns it with its assigned id.
+    fn append(
+        &mut self,
+        conversation: &Conversation,
+        user: UserOutput,
+        body: &str,
+        created_at: DateTime<Utc>,
+    ) -> Result<MessageOutput, HistoryError>;
+
+    /// Returns a page of at most `query.limit` messages of a conversation,
+    /// oldest first. See `HistoryQuery` for which messages end up on the page.
+    fn range(
+        &self,
+        conversation: &Conversation,
+        query: &HistoryQuery,
+    ) -> Result<HistoryPage, HistoryError>;
+
+    /// Returns a single message of a conversation, if it exists.
+    fn get(
+        &self,
+        conversation: &Conversation,
+        id: MessageId,
+    ) -> Result<Option<MessageOutput>, HistoryError>;
+
+    /// Stores the new body, edit time and tombstone of an existing message.
+    fn update(
+        &mut self,
+        conversation: &Conversation,
+        message: &MessageOutput,
+    ) -> Result<(), HistoryError>;
+
+    /// Deletes the entire history of a conversation and returns how many
+    /// messages were removed.
+    fn delete(&mut self, conversation: &Conversation) -> Result<usize, HistoryError>;
+
+    /// Saves the settings of a room, replacing those saved before.
+    fn save_room(&mut self, room: &SavedRoom) -> Result<(), HistoryError>;
+
+    /// Forgets the settings of a room. Its history is deleted separately.
+    fn delete_room(&mut self, id: Uuid) -> Result<(), HistoryError>;
+
+    /// Returns the settings of every saved room.
+    fn load_rooms(&self) -> Result<Vec<SavedRoom>, HistoryError>;
+}
+
+/// The settings of a persistent room that a client created, which are kept
+/// next to its history so that the room is still there after a restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedRoom {
+    /// The user who created the room, whose quota of rooms it counts towards.
+    pub creator: Uuid,
+    pub config: RoomConfig,
+}
+
+/// A history store owned by a single room (or the lobby).
+pub type BoxedHistoryStore = Box<dyn HistoryStore + Send>;
+
+/// Where the chat history is kept. The lobby and every room open a store of
+/// their own from it, which may be running on different threads, so that busy
+/// rooms never hold up the history of quiet ones.
+#[derive(Clone)]
+pub enum HistoryBackend {
+    /// History kept in memory, shared by every store opened from it.
+    Memory(MemoryHistoryStore),
+    /// History kept in a SQLite database, with a connection per store.
+    Sqlite(PathBuf),
+}
+
+impl HistoryBackend {
+    /// Keeps history in the SQLite database at `path`, which is created along
+    /// with its schema if it doesn't exist yet.
+    pub fn sqlite(path: PathBuf) -> Result<Self, HistoryError> {
+        SqliteHistoryStore::open(&path)?;
+        Ok(HistoryBackend::Sqlite(path))
+    }
+
+    /// Opens a new store on the history.
+    pub fn open(&self) -> Result<BoxedHistoryStore, HistoryError> {
+        match self {
+            HistoryBackend::Memory(store) => Ok(Box::new(store.clone())),
+            HistoryBackend::Sqlite(path) => Ok(Box::new(SqliteHistoryStore::open(path)?)),
+        }
+    }
+}
+
+// Locks a mutex. Every write to a store is a single step, so history whose
+// lock was poisoned by a panicking thread is still consistent and is used
+// anyway.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// What a history belongs to. Rooms and direct conversations are kept apart,
+/// so that no room id ever leads to a direct conversation or the other way
+/// round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Conversation {
+    Room(Uuid),
+    /// The direct conversation between two users, the lower id first. Use
+    /// `Conversation::direct` to get the ids in order.
+    Direct(Uuid, Uuid),
+}
+
+impl Conversation {
+    /// The direct conversation between two users, whichever way round they
+    /// are given.
+    pub fn direct(a: Uuid, b: Uuid) -> Self {
+        Conversation::Direct(a.min(b), a.max(b))
+    }
+}
+
+impl fmt::Display for Conversation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Conversation::Room(id) => write!(f, "room {}", id),
+            Conversation::Direct(a, b) => write!(f, "direct conversation of {} and {}", a, b),
+        }
+    }
+}
+
+/// Selects a page of a room's history.
+///
+/// With `after` set, the page holds the messages directly following that
+/// message id (stopping at `before` if that is set too). Otherwise the page
+/// holds the messages directly preceding `before`, or the most recent messages
+/// if neither is set.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HistoryQuery {
+    pub before: Option<MessageId>,
+    pub after: Option<MessageId>,
+    pub limit: usize,
+}
+
+/// A page of messages returned by a history store.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryPage {
+    /// The messages on the page, oldest first.
+    pub messages: Vec<MessageOutput>,
+
+    /// Whether there are more messages beyond the page, in the direction that
+    /// was paged in.
+    pub has_more: bool,
+}
+
+impl HistoryQuery {
+    /// Query for the most recent messages of a room.
+    pub fn latest(limit: usize) -> Self {
+        HistoryQuery {
+            before: None,
+            after: None,
+            limit,
+        }
+    }
+}
+
+impl HistoryPage {
+    // Builds a page from up to `limit + 1` messages, where the extra message
+    // only tells that there is more to fetch. `forward` tells whether the
+    // messages were fetched oldest first (paging forwards) or newest first.
+    fn from_overfetched(mut messages: Vec<MessageOutput>, limit: usize, forward: bool) -> Self {
+        let has_more = messages.len() > limit;
+        messages.truncate(limit);
+        if !forward {
+            messages.reverse();
+        }
+        HistoryPage { messages, has_more }
+    }
+
+    /// The cursor to pass back in a query to continue paging in the same
+    /// direction, if there is anything left. `forward` tells whether the page
+    /// was fetched with `after`.
+    pub fn cursor(&self, forward: bool) -> Option<MessageId> {
+        if !self.has_more {
+            return None;
+        }
+        let edge = if forward {
+            self.messages.last()
+        } else {
+            self.messages.first()
+        };
+        edge.map(|message| message.id)
+    }
+}
+
+/// Error returned by a history store that failed to read or write messages.
+#[derive(Debug)]
+pub enum HistoryError {
+    Sqlite(rusqlite::Error),
+    Corrupt(String),
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryError::Sqlite(e) => write!(f, "sqlite error: {}", e),
+            HistoryError::Corrupt(reason) => write!(f, "corrupt history entry: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for HistoryError {}
+
+impl From<rusqlite::Error> for HistoryError {
+    fn from(e: rusqlite::Error) -> Self {
+        HistoryError::Sqlite(e)
+    }
+}
+
+type SharedHistory = Arc<Mutex<Vec<MessageOutput>>>;
+
+/// Keeps the history of every conversation in memory. Clones of a store share
+/// the history, with a lock per conversation so that they only wait on each
+/// other for the same conversation. Everything is lost when the server stops.
+#[derive(Clone, Default)]
+pub struct MemoryHistoryStore {
+    conversations: Arc<Mutex<HashMap<Conversation, SharedHistory>>>,
+    rooms: Arc<Mutex<HashMap<Uuid, SavedRoom>>>,
+}
+
+impl MemoryHistoryStore {
+    // Returns the history of a conversation, if it has any.
+    fn history(&self, conversation: &Conversation) -> Option<SharedHistory> {
+        lock(&self.conversations).get(conversation).cloned()
+    }
+}
+
+impl HistoryStore for MemoryHistoryStore {
+    fn append(
+        &mut self,
+        conversation: &Conversation,
+        user: UserOutput,
+        body: &str,
+        created_at: DateTime<Utc>,
+    ) -> Result<MessageOutput, HistoryError> {
+        let history = Arc::clone(lock(&self.conversations).entry(*conversation).or_default());
+        let mut history = lock(&history);
+        let id = history.last().map_or(1, |message| message.id + 1);
+        let message = MessageOutput::new(id, user, body, created_at);
+
+        history.push(message.clone());
+        Ok(message)
+    }
+
+    fn range(
+        &self,
+        conversation: &Conversation,
+        query: &HistoryQuery,
+    ) -> Result<HistoryPage, HistoryError> {
+        let history = self.history(conversation);
+        let history = history.as_deref().map(lock);
+        let history = history.as_deref().map(Vec::as_slice).unwrap_or_default();
+
+        // Messages are kept in id order, so the page is a slice of the
+        // history.
+        let start = query.after.map_or(0, |after| {
+            history.partition_point(|message| message.id <= after)
+        });
+        let end = query.before.map_or(history.len(), |before| {
+            history.partition_point(|message| message.id < before)
+        });
+        let history = &history[start..end.max(start)];
+
+        let page = if query.after.is_some() {
+            let messages = history.iter().take(query.limit + 1).cloned().collect();
+            HistoryPage::from_overfetched(messages, query.limit, true)
+        } else {
+            let messages = history
+                .iter()
+                .rev()
+                .take(query.limit + 1)
+                .cloned()
+                .collect();
+            HistoryPage::from_overfetched(messages, query.limit, false)
+        };
+
+        Ok(page)
+    }
+
+    fn get(
+        &self,
+        conversation: &Conversation,
+        id: MessageId,
+    ) -> Result<Option<MessageOutput>, HistoryError> {
+        Ok(self.history(conversation).and_then(|history| {
+            let history = lock(&history);
+            history
+                .binary_search_by_key(&id, |message| message.id)
+                .ok()
+                .map(|index| history[index].clone())
+        }))
+    }
+
+    fn update(
+        &mut self,
+        conversation: &Conversation,
+        message: &MessageOutput,
+    ) -> Result<(), HistoryError> {
+        if let Some(history) = self.history(conversation) {
+            let mut history = lock(&history);
+            if let Ok(index) = history.binary_search_by_key(&message.id, |message| message.id) {
+                history[index] = message.clone();
+            }
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, conversation: &Conversation) -> Result<usize, HistoryError> {
+        let history = lock(&self.conversations).remove(conversation);
+        Ok(history.map_or(0, |history| lock(&history).len()))
+    }
+
+    fn save_room(&mut self, room: &SavedRoom) -> Result<(), HistoryError> {
+        lock(&self.rooms).insert(room.config.id, room.clone());
+        Ok(())
+    }
+
+    fn delete_room(&mut self, id: Uuid) -> Result<(), HistoryError> {
+        lock(&self.rooms).remove(&id);
+        Ok(())
+    }
+
+    fn load_rooms(&self) -> Result<Vec<SavedRoom>, HistoryError> {
+        Ok(lock(&self.rooms).values().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const MESSAGES: u64 = 10;
+
+    // A store of each kind, holding the same history.
+    fn stores() -> Vec<(&'static str, BoxedHistoryStore)> {
+        let mut stores: Vec<(&'static str, BoxedHistoryStore)> = vec![
+            ("memory", Box::new(MemoryHistoryStore::default())),
+            (
+                "sqlite",
+                Box::new(SqliteHistoryStore::open(":memory:").unwrap()),
+            ),
+        ];
+
+        let user = UserOutput::new(Uuid::from_u128(1), "joel");
+        for (_, store) in &mut stores {
+            for i in 1..=MESSAGES {
+                let created_at = Utc.timestamp_opt(1_600_000_000 + i as i64, 0).unwrap();
+                let message = store
+                    .append(&room(), user.clone(), &format!("message {}", i), created_at)
+                    .unwrap();
+                assert_eq!(message.id, i);
+            }
+        }
+        stores
+    }
+
+    fn room() -> Conversation {
+        Conversation::Room(Uuid::from_u128(2))
+    }
+
+    fn ids(page: &HistoryPage) -> Vec<MessageId> {
+        page.messages.iter().map(|message| message.id).collect()
+    }
+
+    // Pages through the whole history with the cursors the pages give,
+    // backwards from the latest messages or forwards from the start.
+    fn page_through(store: &BoxedHistoryStore, forward: bool) -> Vec<Vec<MessageId>> {
+        let mut query = HistoryQuery {
+            after: if forward { Some(0) } else { None },
+            ..HistoryQuery::latest(3)
+        };
+        let mut pages = Vec::new();
+        loop {
+            let page = store.range(&room(), &query).unwrap();
+            pages.push(ids(&page));
+            match page.cursor(forward) {
+                Some(cursor) if forward => query.after = Some(cursor),
+                Some(cursor) => query.before = Some(cursor),
+                None => return pages,
+            }
+        }
+    }
+
+    #[test]
+    fn pages_backwards() {
+        for (name, store) in stores() {
+            assert_eq!(
+                page_through(&store, false),
+                vec![vec![8, 9, 10], vec![5, 6, 7], vec![2, 3, 4], vec![1]],
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn pages_forwards() {
+        for (name, store) in stores() {
+            assert_eq!(
+                page_through(&store, true),
+                vec![vec![1, 2, 3], vec![4, 5, 6], vec![7, 8, 9], vec![10]],
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn backends_agree_on_every_page() {
+        let stores = stores();
+        let limits = [0, 1, 3, MESSAGES as usize, MESSAGES as usize + 1];
+        let bounds = [None, Some(0), Some(1), Some(5), Some(MESSAGES), Some(20)];
+
+        for &limit in &limits {
+            for &before in &bounds {
+                for &after in &bounds {
+                    let query = HistoryQuery {
+                        before,
+                        after,
+                        limit,
+                    };
+                    let memory = stores[0].1.range(&room(), &query).unwrap();
+                    let sqlite = stores[1].1.range(&room(), &query).unwrap();
+                    assert_eq!(memory, sqlite, "{:?}", query);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pages_between_bounds() {
+        for (name, store) in stores() {
+            let query = HistoryQuery {
+                before: Some(8),
+                after: Some(3),
+                limit: 3,
+            };
+            let page = store.range(&room(), &query).unwrap();
+            assert_eq!(ids(&page), vec![4, 5, 6], "{}", name);
+            assert!(page.has_more, "{}", name);
+            assert_eq!(page.cursor(true), Some(6), "{}", name);
+
+            let query = HistoryQuery {
+                after: Some(6),
+                ..query
+            };
+            let page = store.range(&room(), &query).unwrap();
+            assert_eq!(ids(&page), vec![7], "{}", name);
+            assert_eq!(page.cursor(true), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn conversations_are_kept_apart() {
+        let a = Uuid::from_u128(1);
+        let b = Uuid::from_u128(2);
+        assert_eq!(Conversation::direct(a, b), Conversation::direct(b, a));
+
+        for (name, mut store) in stores() {
+            // The direct conversation of two users never shares the history of
+            // a room, even one with the id of either of them.
+            let direct = Conversation::direct(a, b);
+            let page = store.range(&direct, &HistoryQuery::latest(5)).unwrap();
+            assert!(page.messages.is_empty(), "{}", name);
+
+            let user = UserOutput::new(a, "joel");
+            let message = store.append(&direct, user, "hi", Utc::now()).unwrap();
+            assert_eq!(message.id, 1, "{}", name);
+
+            assert_eq!(
+                store.delete(&room()).unwrap(),
+                MESSAGES as usize,
+                "{}",
+                name
+            );
+            assert_eq!(store.get(&direct, 1).unwrap(), Some(message), "{}", name);
+            assert_eq!(store.get(&room(), 1).unwrap(), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn updates_messages() {
+        for (name, mut store) in stores() {
+            let mut message = store.get(&room(), 4).unwrap().unwrap();
+            message.body = String::new();
+            message.deleted = true;
+            message.edited_at = Some(Utc.timestamp_opt(1_700_000_000, 5).unwrap());
+            store.update(&room(), &message).unwrap();
+
+            assert_eq!(store.get(&room(), 4).unwrap(), Some(message), "{}", name);
+        }
+    }
+}
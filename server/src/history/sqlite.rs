@@ -0,0 +1,316 @@
+use super::{Conversation, HistoryError, HistoryPage, HistoryQuery, HistoryStore, SavedRoom};
+use crate::config::RoomConfig;
+use crate::proto::{MessageId, MessageOutput, UserOutput};
+use chrono::{DateTime, TimeZone, Utc};
+use rusqlite::{params, Connection, Row, NO_PARAMS};
+use std::path::Path;
+use std::time::Duration;
+use uuid::Uuid;
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+/// How long to wait for another connection to finish writing before giving up.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keeps the history of every room in an embedded SQLite database so that it
+/// survives restarts of the server. Every store has a connection of its own;
+/// the database is in WAL mode so that readers don't wait on writers.
+pub struct SqliteHistoryStore {
+    conn: Connection,
+}
+
+impl SqliteHistoryStore {
+    /// Opens (or creates) the database at `path` and makes sure the schema
+    /// exists.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, HistoryError> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.query_row("PRAGMA journal_mode = WAL", NO_PARAMS, |_| Ok(()))?;
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                room_id    TEXT    NOT NULL,
+                id         INTEGER NOT NULL,
+                user_id    TEXT    NOT NULL,
+                user_name  TEXT    NOT NULL,
+                body       TEXT    NOT NULL,
+                created_at INTEGER NOT NULL,
+                edited_at  INTEGER,
+                deleted    INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (room_id, id)
+            );
+            CREATE TABLE IF NOT EXISTS rooms (
+                id       TEXT NOT NULL PRIMARY KEY,
+                creator  TEXT NOT NULL,
+                settings TEXT NOT NULL
+            );",
+        )?;
+
+        Ok(SqliteHistoryStore { conn })
+    }
+}
+
+// Timestamps are stored as nanoseconds since the unix epoch so that they can be
+// compared and sorted by SQLite. That covers the years 1677 to 2262, anything
+// outside of that can't be stored.
+fn to_nanos(timestamp: DateTime<Utc>) -> Result<i64, HistoryError> {
+    timestamp
+        .timestamp_nanos_opt()
+        .ok_or_else(|| HistoryError::Corrupt(format!("timestamp {} out of range", timestamp)))
+}
+
+fn from_nanos(nanos: i64) -> Result<DateTime<Utc>, HistoryError> {
+    Utc.timestamp_opt(
+        nanos.div_euclid(NANOS_PER_SEC),
+        nanos.rem_euclid(NANOS_PER_SEC) as u32,
+    )
+    .single()
+    .ok_or_else(|| HistoryError::Corrupt(format!("timestamp {} out of range", nanos)))
+}
+
+// The key a conversation's messages are stored under, in the `room_id` column.
+// Rooms are stored under their bare id, so no room can reach a direct
+// conversation.
+fn conversation_key(conversation: &Conversation) -> String {
+    match conversation {
+        Conversation::Room(id) => id.to_string(),
+        Conversation::Direct(a, b) => format!("direct:{}:{}", a, b),
+    }
+}
+
+fn parse_uuid(value: &str) -> Result<Uuid, HistoryError> {
+    Uuid::parse_str(value).map_err(|_| HistoryError::Corrupt(format!("invalid uuid {}", value)))
+}
+
+fn message_from_row(row: &Row) -> Result<MessageOutput, HistoryError> {
+    let id: i64 = row.get(0)?;
+    let user_id: String = row.get(1)?;
+    let user_name: String = row.get(2)?;
+    let body: String = row.get(3)?;
+    let created_at: i64 = row.get(4)?;
+    let edited_at: Option<i64> = row.get(5)?;
+    let deleted: bool = row.get(6)?;
+
+    let mut message = MessageOutput::new(
+        id as MessageId,
+        UserOutput::new(parse_uuid(&user_id)?, &user_name),
+        &body,
+        from_nanos(created_at)?,
+    );
+    message.edited_at = edited_at.map(from_nanos).transpose()?;
+    message.deleted = deleted;
+    Ok(message)
+}
+
+impl HistoryStore for SqliteHistoryStore {
+    fn append(
+        &mut self,
+        conversation: &Conversation,
+        user: UserOutput,
+        body: &str,
+        created_at: DateTime<Utc>,
+    ) -> Result<MessageOutput, HistoryError> {
+        // Numbering and inserting the message in one statement keeps it atomic
+        // with other connections writing to the same database.
+        self.conn.execute(
+            "INSERT INTO messages (room_id, id, user_id, user_name, body, created_at)
+             SELECT ?1, COALESCE(MAX(id), 0) + 1, ?2, ?3, ?4, ?5
+             FROM messages WHERE room_id = ?1",
+            params![
+                conversation_key(conversation),
+                user.id.to_string(),
+                user.name,
+                body,
+                to_nanos(created_at)?,
+            ],
+        )?;
+        let id: i64 = self.conn.query_row(
+            "SELECT id FROM messages WHERE rowid = last_insert_rowid()",
+            NO_PARAMS,
+            |row| row.get(0),
+        )?;
+
+        Ok(MessageOutput::new(id as MessageId, user, body, created_at))
+    }
+
+    fn range(
+        &self,
+        conversation: &Conversation,
+        query: &HistoryQuery,
+    ) -> Result<HistoryPage, HistoryError> {
+        // Fetch one message more than asked for to find out if there is more
+        // to page through.
+        let forward = query.after.is_some();
+        let mut stmt = self.conn.prepare(if forward {
+            "SELECT id, user_id, user_name, body, created_at, edited_at, deleted
+             FROM messages
+             WHERE room_id = ?1
+               AND (?2 IS NULL OR id < ?2)
+               AND (?3 IS NULL OR id > ?3)
+             ORDER BY id ASC LIMIT ?4"
+        } else {
+            "SELECT id, user_id, user_name, body, created_at, edited_at, deleted
+             FROM messages
+             WHERE room_id = ?1
+               AND (?2 IS NULL OR id < ?2)
+               AND (?3 IS NULL OR id > ?3)
+             ORDER BY id DESC LIMIT ?4"
+        })?;
+        let mut rows = stmt.query(params![
+            conversation_key(conversation),
+            query.before.map(|id| id as i64),
+            query.after.map(|id| id as i64),
+            query.limit as i64 + 1,
+        ])?;
+
+        let mut messages = Vec::new();
+        while let Some(row) = rows.next()? {
+            messages.push(message_from_row(row)?);
+        }
+        Ok(HistoryPage::from_overfetched(
+            messages,
+            query.limit,
+            forward,
+        ))
+    }
+
+    fn get(
+        &self,
+        conversation: &Conversation,
+        id: MessageId,
+    ) -> Result<Option<MessageOutput>, HistoryError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, user_id, user_name, body, created_at, edited_at, deleted
+             FROM messages
+             WHERE room_id = ?1 AND id = ?2",
+        )?;
+        let mut rows = stmt.query(params![conversation_key(conversation), id as i64])?;
+
+        match rows.next()? {
+            Some(row) => Ok(Some(message_from_row(row)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn update(
+        &mut self,
+        conversation: &Conversation,
+        message: &MessageOutput,
+    ) -> Result<(), HistoryError> {
+        self.conn.execute(
+            "UPDATE messages SET body = ?3, edited_at = ?4, deleted = ?5
+             WHERE room_id = ?1 AND id = ?2",
+            params![
+                conversation_key(conversation),
+                message.id as i64,
+                message.body,
+                message.edited_at.map(to_nanos).transpose()?,
+                message.deleted,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn delete(&mut self, conversation: &Conversation) -> Result<usize, HistoryError> {
+        Ok(self.conn.execute(
+            "DELETE FROM messages WHERE room_id = ?1",
+            params![conversation_key(conversation)],
+        )?)
+    }
+
+    fn save_room(&mut self, room: &SavedRoom) -> Result<(), HistoryError> {
+        // The settings are stored as JSON so that new ones don't need a
+        // change of the schema.
+        let settings = serde_json::to_string(&room.config)
+            .map_err(|e| HistoryError::Corrupt(format!("room {}: {}", room.config.id, e)))?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO rooms (id, creator, settings) VALUES (?1, ?2, ?3)",
+            params![
+                room.config.id.to_string(),
+                room.creator.to_string(),
+                settings
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn delete_room(&mut self, id: Uuid) -> Result<(), HistoryError> {
+        self.conn
+            .execute("DELETE FROM rooms WHERE id = ?1", params![id.to_string()])?;
+        Ok(())
+    }
+
+    fn load_rooms(&self) -> Result<Vec<SavedRoom>, HistoryError> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id, creator, settings FROM rooms")?;
+        let rows = stmt.query_map(NO_PARAMS, |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })?;
+
+        rows.map(|row| {
+            let (id, creator, settings) = row?;
+            let corrupt =
+                |e: serde_json::Error| HistoryError::Corrupt(format!("room {}: {}", id, e));
+
+            // Settings saved by another version of the server may include ones
+            // that this version doesn't have, which are left out rather than
+            // refusing to load the room.
+            let settings: serde_json::Map<String, serde_json::Value> =
+                serde_json::from_str(&settings).map_err(corrupt)?;
+            let settings = settings
+                .into_iter()
+                .filter(|(field, _)| RoomConfig::FIELDS.contains(&field.as_str()))
+                .collect();
+            let config =
+                serde_json::from_value(serde_json::Value::Object(settings)).map_err(corrupt)?;
+            Ok(SavedRoom {
+                creator: parse_uuid(&creator)?,
+                config,
+            })
+        })
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_rooms_saved_with_settings_since_removed() {
+        let mut store = SqliteHistoryStore::open(":memory:").unwrap();
+        let id = Uuid::from_u128(1);
+        store
+            .conn
+            .execute(
+                "INSERT INTO rooms (id, creator, settings) VALUES (?1, ?2, ?3)",
+                params![
+                    id.to_string(),
+                    Uuid::from_u128(2).to_string(),
+                    format!(
+                        r#"{{"id":"{}","name":"Old room","retired-setting":true}}"#,
+                        id
+                    )
+                ],
+            )
+            .unwrap();
+
+        let rooms = store.load_rooms().unwrap();
+        assert_eq!(rooms.len(), 1);
+        assert_eq!(rooms[0].config.id, id);
+        assert_eq!(rooms[0].config.name, "Old room");
+
+        // Saving it again leaves the setting out.
+        store.save_room(&rooms[0]).unwrap();
+        let settings: String = store
+            .conn
+            .query_row("SELECT settings FROM rooms", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert!(!settings.contains("retired-setting"));
+    }
+}
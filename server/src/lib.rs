@@ -0,0 +1,16 @@
+//! WebSocket chat server. The binary serves the chat, the library is there for
+//! benchmarks to drive the lobby and rooms directly.
+
+pub mod auth;
+pub mod config;
+pub mod credentials;
+pub mod history;
+pub mod lobby;
+pub mod messages;
+pub mod permissions;
+pub mod proto;
+pub mod rate_limit;
+pub mod rooms;
+pub mod start_connection;
+pub mod validation;
+pub mod ws;
@@ -1,79 +1,255 @@
-use crate::messages::{ClientActorMessage, Connect, Disconnect, Join, Typing, WsMessage};
+use crate::config::{Config, LimitConfig, RoomConfig, TimeoutConfig};
+use crate::history::{
+    BoxedHistoryStore, Conversation, HistoryBackend, HistoryError, HistoryQuery, SavedRoom,
+};
+use crate::messages::{
+    ClaimSeat, Connect, CreateInvite, CreateRoom, DeleteRoom, Direct, DirectHistory, Evict,
+    FindRoom, Place, Placement, RoomDeleted, RoomUpdated, SlowClient, Unsubscribe, UpdateRoom,
+    WsMessage, SESSION_REPLACED_CLOSE_CODE, TOO_SLOW_CLOSE_CODE,
+};
 use crate::proto::*;
 use crate::rooms::ChatRoom;
-use actix::prelude::{Actor, Context, Handler, Recipient};
-use chrono::{DateTime, Utc};
+use actix::prelude::{
+    Actor, Addr, Arbiter, AsyncContext, Context, Handler, MessageResult, Recipient, SendError,
+};
+use actix_web_actors::ws::{CloseCode, CloseReason};
+use bytestring::ByteString;
+use chrono::Utc;
 use std::collections::HashMap;
 use uuid::Uuid;
 
 type Socket = Recipient<WsMessage>;
 
+/// A seat in a room that a resume token gives back.
+struct Seat {
+    client_id: Uuid,
+    room_id: Uuid,
+}
+
+/// A running room and what the lobby lists about it.
+struct RoomEntry {
+    addr: Addr<ChatRoom>,
+    summary: Room,
+    lifecycle: Lifecycle,
+    /// The user who created the room, if a client created it.
+    creator: Option<Uuid>,
+}
+
+/// A client's place in one of its rooms, as last told by the room.
+struct Member {
+    /// The client's socket, unless it lost its connection.
+    addr: Option<Socket>,
+    /// The client's name in the room, unless it is still waiting for a seat.
+    username: Option<String>,
+}
+
 /// The lobby keeps track of all available chatrooms that clients can connect
-/// to and the socket for every connected client.
+/// to and routes clients to them. Every room is an actor of its own, spread
+/// over a pool of arbiters (threads), and tells the lobby who is in it.
 pub struct Lobby {
-    sessions: HashMap<Uuid, Socket>, // self id to self
-    rooms: HashMap<Uuid, ChatRoom>,  // room id to a chatroom.
-}
+    rooms: HashMap<Uuid, RoomEntry>, // room id to a chatroom.
+    members: HashMap<Uuid, HashMap<Uuid, Member>>, // self id to room id to the client's place.
+    history: BoxedHistoryStore,      // history of direct messages.
 
-impl Default for Lobby {
-    fn default() -> Self {
-        let mut lobby = Lobby {
-            sessions: HashMap::new(),
-            rooms: HashMap::new(),
-        };
+    /// Where rooms open their own history stores.
+    history_backend: HistoryBackend,
 
-        let default_room_id = Uuid::new_v4();
+    /// Maps resume tokens to the seat they resume.
+    resume_tokens: HashMap<Uuid, Seat>,
 
-        lobby.rooms.insert(
-            default_room_id,
-            ChatRoom::new(default_room_id, "Default room".to_string(), 10),
-        );
+    /// Connected clients, which are kept posted about rooms being added,
+    /// updated and removed.
+    subscribers: Vec<Socket>,
+
+    /// Arbiters that rooms are started in, taking turns.
+    arbiters: Vec<Arbiter>,
+    next_arbiter: usize,
+
+    starter_rooms: Vec<RoomConfig>,
+    create_rooms_on_join: bool,
+    limits: LimitConfig,
+    timeouts: TimeoutConfig,
+}
 
-        let joels_room_id = Uuid::new_v4();
+impl Lobby {
+    pub fn new(history_backend: HistoryBackend, config: &Config) -> Result<Self, HistoryError> {
+        Ok(Lobby {
+            rooms: HashMap::new(),
+            members: HashMap::new(),
+            history: history_backend.open()?,
+            history_backend,
+            resume_tokens: HashMap::new(),
+            subscribers: Vec::new(),
+            arbiters: (0..config.room_threads()).map(|_| Arbiter::new()).collect(),
+            next_arbiter: 0,
+            starter_rooms: config.rooms.clone(),
+            create_rooms_on_join: config.create_rooms_on_join,
+            limits: config.limits.clone(),
+            timeouts: config.timeouts.clone(),
+        })
+    }
 
-        lobby.rooms.insert(
-            joels_room_id,
-            ChatRoom::new(joels_room_id, "Joel's room".to_string(), 10),
+    // Starts a new room in the next arbiter in line, created by `creator` if a
+    // client created it. Fails if the room can't open a store for its history.
+    fn start_room(
+        &mut self,
+        config: RoomConfig,
+        creator: Option<Uuid>,
+        ctx: &mut Context<Self>,
+    ) -> Result<Addr<ChatRoom>, HistoryError> {
+        let history = self.history_backend.open()?;
+        let arbiter = &self.arbiters[self.next_arbiter];
+        self.next_arbiter = (self.next_arbiter + 1) % self.arbiters.len();
+
+        let id = config.id;
+        let lifecycle = config.lifecycle;
+        let max_clients = config.max_clients(&self.limits);
+        let summary = Room::new(
+            id,
+            config.name.clone(),
+            config.topic.clone(),
+            config.access(),
+            0,
+            max_clients,
+        );
+        let lobby = ctx.address();
+        let limits = self.limits.clone();
+        let timeouts = self.timeouts.clone();
+
+        let addr = ChatRoom::start_in_arbiter(&arbiter.handle(), move |_| {
+            let roles = config.roles();
+            let mut room = ChatRoom::new(
+                id,
+                config.name,
+                max_clients,
+                history,
+                lobby,
+                limits,
+                timeouts,
+            );
+            room.topic = config.topic;
+            room.roles = roles;
+            room.default_role = config.default_role;
+            room.lifecycle = config.lifecycle;
+            room.creator = creator;
+            room.password_hash = config.password_hash;
+            room.private = config.private;
+            room.invite_only = config.invite_only;
+            room
+        });
+
+        if !summary.access.private {
+            self.send_to_subscribers(&Output::RoomAdded(RoomAddedOutput::new(summary.clone())));
+        }
+        self.rooms.insert(
+            id,
+            RoomEntry {
+                addr: addr.clone(),
+                summary,
+                lifecycle,
+                creator,
+            },
         );
 
-        lobby
+        Ok(addr)
     }
-}
 
-impl Lobby {
-    fn send_message(&self, message: &str, id_to: &Uuid) {
-        if let Some(socket_recipient) = self.sessions.get(id_to) {
-            let _ = socket_recipient.do_send(WsMessage(message.to_owned()));
-        } else {
-            println!("Attempting to send message but couldn't find client id.");
+    // Returns true if a room that isn't running left history behind, which
+    // only the room itself may read. Errors count as history, to be safe.
+    fn has_history(&self, room_id: Uuid) -> bool {
+        match self
+            .history
+            .range(&Conversation::Room(room_id), &HistoryQuery::latest(1))
+        {
+            Ok(page) => !page.messages.is_empty(),
+            Err(e) => {
+                println!("Failed to read the history of room {}: {}", room_id, e);
+                true
+            }
         }
     }
 
-    // Sends a message to every client connected to a chatroom.
-    fn send_to_everyone(&self, room_id: &Uuid, message: &String) {
-        self.rooms
-            .get(room_id)
-            .unwrap()
-            .clients
-            .keys()
-            .for_each(|client_id| self.send_message(message, client_id));
+    // Sends an output to every connected client. Clients that can't keep up
+    // are disconnected rather than left with a stale list of rooms.
+    fn send_to_subscribers(&mut self, output: &Output) {
+        let text = output.to_text();
+        self.subscribers
+            .retain(|addr| match addr.try_send(WsMessage::Text(text.clone())) {
+                Ok(()) => true,
+                Err(SendError::Full(_)) => {
+                    addr.do_send(WsMessage::Close(Some(CloseReason {
+                        code: CloseCode::Other(TOO_SLOW_CLOSE_CODE),
+                        description: Some("Too slow to keep up with the chat".to_string()),
+                    })));
+                    false
+                }
+                Err(SendError::Closed(_)) => false,
+            });
+    }
+
+    // Finds the name of a client that is connected and seated in any room.
+    fn find_connected_user(&self, client_id: &Uuid) -> Option<UserOutput> {
+        self.members
+            .get(client_id)?
+            .values()
+            .filter(|member| member.addr.is_some())
+            .find_map(|member| member.username.as_ref())
+            .map(|username| UserOutput::new(*client_id, username))
     }
 
-    // Sends a message to every client connected to a chatroom except one client
-    // specified by `self_id`.
-    fn send_to_everyone_except_self(&self, room_id: &Uuid, self_id: &Uuid, message: &String) {
-        self.rooms
-            .get(room_id)
-            .unwrap()
-            .clients
-            .keys()
-            .filter(|client_id| *client_id.to_owned() != *self_id)
-            .for_each(|client_id| self.send_message(message, client_id));
+    // Finds the socket of a client that is connected to any room.
+    fn find_socket(&self, client_id: &Uuid) -> Option<&Socket> {
+        self.members
+            .get(client_id)?
+            .values()
+            .find_map(|member| member.addr.as_ref())
+    }
+
+    // Sends a direct message to one of its parties, who is disconnected if
+    // its queue of outputs is full.
+    fn send_direct_message(&self, text: &ByteString, client_id: &Uuid, ctx: &mut Context<Self>) {
+        let addr = match self.find_socket(client_id) {
+            Some(addr) => addr,
+            None => return,
+        };
+
+        if let Err(SendError::Full(_)) = addr.try_send(WsMessage::Text(text.clone())) {
+            ctx.notify(SlowClient {
+                client_id: *client_id,
+            });
+        }
     }
 }
 
 impl Actor for Lobby {
     type Context = Context<Self>;
+
+    // Starts the rooms that exist from the start: those in the configuration
+    // and the persistent rooms that clients created before a restart.
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let saved_rooms = self.history.load_rooms().unwrap_or_else(|e| {
+            println!("Failed to load saved rooms: {}", e);
+            Vec::new()
+        });
+
+        let configured = std::mem::take(&mut self.starter_rooms)
+            .into_iter()
+            .map(|room| (room, None));
+        let saved = saved_rooms
+            .into_iter()
+            .map(|room| (room.config, Some(room.creator)));
+        for (room, creator) in configured.chain(saved) {
+            let id = room.id;
+            // The configuration wins over a room saved under the same id.
+            if self.rooms.contains_key(&id) {
+                println!("Room {} is configured, ignoring the saved room", id);
+                continue;
+            }
+            if let Err(e) = self.start_room(room, creator, ctx) {
+                println!("Failed to start room {}: {}", id, e);
+            }
+        }
+    }
 }
 
 impl Handler<Connect> for Lobby {
@@ -83,201 +259,450 @@ impl Handler<Connect> for Lobby {
     // on the server to send information about all the available rooms to the
     // client. This happens before the server gets information about what
     // username the client has and what room the client wants to join.
+    // The client is kept posted about changes to the rooms from then on.
     fn handle(&mut self, msg: Connect, _: &mut Context<Self>) {
-        let _ = msg.addr.do_send(WsMessage(
-            serde_json::to_string(&Output::Rooms(RoomsOutput::new(
+        msg.addr.do_send(WsMessage::Text(
+            Output::Rooms(RoomsOutput::new(
                 self.rooms
                     .values()
-                    .map(|room| {
-                        Room::new(
-                            room.id.clone(),
-                            room.name.clone(),
-                            room.clients.len(),
-                            room.max_clients,
-                        )
-                    })
+                    .filter(|room| !room.summary.access.private)
+                    .map(|room| room.summary.clone())
                     .collect(),
-            )))
-            .unwrap(),
+            ))
+            .to_text(),
         ));
+
+        if !self.subscribers.contains(&msg.addr) {
+            self.subscribers.push(msg.addr);
+        }
     }
 }
 
-impl Handler<Join> for Lobby {
+impl Handler<Unsubscribe> for Lobby {
     type Result = ();
 
-    fn handle(&mut self, msg: Join, _: &mut Context<Self>) {
-        // Create a room if necessary.
-        if self.rooms.get(&msg.lobby_id).is_none() {
-            self.rooms.insert(
-                msg.lobby_id.clone(),
-                ChatRoom::new(msg.lobby_id.clone(), format!("{}'s room", msg.username), 10),
-            );
+    fn handle(&mut self, msg: Unsubscribe, _: &mut Context<Self>) {
+        self.subscribers.retain(|addr| *addr != msg.addr);
+    }
+}
+
+impl Handler<FindRoom> for Lobby {
+    type Result = MessageResult<FindRoom>;
+
+    fn handle(&mut self, msg: FindRoom, ctx: &mut Context<Self>) -> Self::Result {
+        // Authenticated users keep their id across connections, joining a room
+        // from a new connection takes over from the one they already had in it.
+        let member = self
+            .members
+            .get(&msg.self_id)
+            .and_then(|rooms| rooms.get(&msg.room_id));
+        if let Some(member) = member {
+            if member.addr.as_ref() != Some(&msg.addr) {
+                if let Some(room) = self.rooms.get(&msg.room_id) {
+                    room.addr.do_send(Evict {
+                        client_id: msg.self_id,
+                        reason: CloseReason {
+                            code: CloseCode::Other(SESSION_REPLACED_CLOSE_CODE),
+                            description: Some(
+                                "Session taken over by another connection".to_string(),
+                            ),
+                        },
+                    });
+                }
+            }
         }
 
-        // Echo to everyone in the room that a new client just joined.
-        self.send_to_everyone_except_self(
-            &msg.lobby_id,
-            &msg.self_id,
-            &serde_json::to_string(&Output::UserJoined(UserJoinedOutput::new(UserOutput::new(
-                msg.self_id,
-                &msg.username,
-            ))))
-            .unwrap(),
-        );
+        // Create a room if necessary, and allowed.
+        let addr = match self.rooms.get(&msg.room_id) {
+            Some(room) => Some(room.addr.clone()),
+            // Rooms that are gone but left history behind aren't created
+            // again, or their history would be handed to whoever joins first.
+            None if self.create_rooms_on_join && !self.has_history(msg.room_id) => {
+                // The client creating the room owns it.
+                let started = self.start_room(
+                    RoomConfig {
+                        id: msg.room_id,
+                        name: format!("{}'s room", msg.username),
+                        topic: None,
+                        max_clients: msg.max_clients,
+                        owners: vec![msg.self_id],
+                        moderators: Vec::new(),
+                        members: Vec::new(),
+                        guests: Vec::new(),
+                        default_role: Role::default(),
+                        lifecycle: Lifecycle::Ephemeral,
+                        password_hash: None,
+                        private: false,
+                        invite_only: false,
+                    },
+                    Some(msg.self_id),
+                    ctx,
+                );
+                match started {
+                    Ok(addr) => Some(addr),
+                    Err(e) => {
+                        println!("Failed to start room {}: {}", msg.room_id, e);
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
 
-        // Get a mutable reference to the current room.
-        let current_room = self.rooms.get_mut(&msg.lobby_id).unwrap();
+        MessageResult(addr)
+    }
+}
 
-        // Add the client to the chatroom.
-        current_room.add_client(&msg.self_id, msg.username.clone());
+impl Handler<ClaimSeat> for Lobby {
+    type Result = MessageResult<ClaimSeat>;
 
-        // Store the address of the client in the sessions hashmap.
-        self.sessions.insert(msg.self_id, msg.addr);
+    fn handle(&mut self, msg: ClaimSeat, _: &mut Context<Self>) -> Self::Result {
+        let claimable = self
+            .resume_tokens
+            .get(&msg.token)
+            .is_some_and(|seat| msg.self_id.is_none_or(|self_id| self_id == seat.client_id));
+        if !claimable {
+            return MessageResult(None);
+        }
 
-        // Get the chat history for the current room.
-        let room_chat_history = current_room.history.clone();
+        // Resume tokens can only be used once.
+        let seat = self.resume_tokens.remove(&msg.token).and_then(|seat| {
+            let room = self.rooms.get(&seat.room_id)?;
+            Some((seat.client_id, seat.room_id, room.addr.clone()))
+        });
 
-        // Get all connected clients information from the current room.
-        let connected_clients: Vec<UserOutput> = current_room
-            .clients
-            .iter()
-            .map(|(client_id, client_name)| UserOutput::new(client_id.clone(), client_name))
-            .collect();
-        // Get all clients that are currently typing.
-        let typing_clients = current_room.get_typing_clients();
-
-        // Send the client information that the join was successful, along with
-        // information about other connected clients and the history of the
-        // chatroom.
-        self.send_message(
-            &serde_json::to_string(&Output::Joined(JoinedOutput::new(
-                UserOutput::new(msg.self_id, &msg.username),
-                connected_clients,
-                room_chat_history,
-                typing_clients,
-            )))
-            .unwrap(),
-            &msg.self_id,
-        );
+        MessageResult(seat)
     }
 }
 
-impl Handler<Disconnect> for Lobby {
+impl Handler<Placement> for Lobby {
     type Result = ();
 
-    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
-        if self.sessions.remove(&msg.self_id).is_some() {
-            // Get a mutable reference to the current room.
-            let current_room = self.rooms.get_mut(&msg.room_id).unwrap();
-
-            // Clone the username from the current room.
-            let username = current_room.get_username(&msg.self_id).unwrap().clone();
-
-            // Remove the client from the current room.
-            current_room.remove_client(&msg.self_id);
-
-            // If the client was typing, send out a message that they've stopped
-            // typing to all clients.
-            if current_room.remove_typing_client(&msg.self_id) {
-                self.send_to_everyone(
-                    &msg.room_id,
-                    &serde_json::to_string(&Output::Typing(TypingOutput::new(
-                        TypingInput::Stopped,
-                        UserOutput::new(msg.self_id.clone(), &username),
-                    )))
-                    .unwrap(),
+    fn handle(&mut self, msg: Placement, _: &mut Context<Self>) {
+        let client_id = msg.client_id;
+        let room_id = msg.room_id;
+
+        match msg.place {
+            None => {
+                // The client's resume token is no use anymore.
+                self.resume_tokens
+                    .retain(|_, seat| seat.client_id != client_id || seat.room_id != room_id);
+
+                if let Some(rooms) = self.members.get_mut(&client_id) {
+                    rooms.remove(&room_id);
+                    if rooms.is_empty() {
+                        self.members.remove(&client_id);
+                    }
+                }
+            }
+            Some(Place::Waiting { addr }) => {
+                self.members.entry(client_id).or_default().insert(
+                    room_id,
+                    Member {
+                        addr: Some(addr),
+                        username: None,
+                    },
+                );
+            }
+            Some(Place::Seated {
+                username,
+                addr,
+                resume_token,
+            }) => {
+                // Only the newest token resumes the seat.
+                self.resume_tokens
+                    .retain(|_, seat| seat.client_id != client_id || seat.room_id != room_id);
+                self.resume_tokens
+                    .insert(resume_token, Seat { client_id, room_id });
+
+                self.members.entry(client_id).or_default().insert(
+                    room_id,
+                    Member {
+                        addr: Some(addr),
+                        username: Some(username),
+                    },
                 );
             }
+            Some(Place::Detached) => {
+                let member = self
+                    .members
+                    .get_mut(&client_id)
+                    .and_then(|rooms| rooms.get_mut(&room_id));
+                if let Some(member) = member {
+                    member.addr = None;
+                }
+            }
+        }
+    }
+}
 
-            // Send message to all other clients in the same room that the
-            // client has disconnected.
-            self.send_to_everyone(
-                &msg.room_id,
-                &serde_json::to_string(&Output::UserLeft(UserLeftOutput::new(
-                    msg.self_id,
-                    &username,
-                )))
-                .unwrap(),
-            );
+impl Handler<RoomUpdated> for Lobby {
+    type Result = ();
+
+    fn handle(&mut self, msg: RoomUpdated, _: &mut Context<Self>) {
+        let room = match self.rooms.get_mut(&msg.room.id) {
+            Some(room) if room.summary != msg.room => room,
+            _ => return,
+        };
+
+        // Clients only know about public rooms from the list, a room that is
+        // made private or public leaves or joins it.
+        let was_private = room.summary.access.private;
+        room.summary = msg.room.clone();
+        let output = match (was_private, msg.room.access.private) {
+            (false, false) => Output::RoomUpdated(RoomUpdatedOutput::new(msg.room)),
+            (false, true) => Output::RoomRemoved(RoomRemovedOutput::new(msg.room.id)),
+            (true, false) => Output::RoomAdded(RoomAddedOutput::new(msg.room)),
+            (true, true) => return,
+        };
+        self.send_to_subscribers(&output);
+    }
+}
+
+impl Handler<SlowClient> for Lobby {
+    type Result = ();
+
+    fn handle(&mut self, msg: SlowClient, _: &mut Context<Self>) {
+        let rooms = match self.members.get(&msg.client_id) {
+            Some(rooms) => rooms,
+            None => return,
+        };
+
+        println!(
+            "Disconnecting client {} that can't keep up with its outputs.",
+            msg.client_id
+        );
+
+        // The client's connection is closed, so it is taken out of every room
+        // it is in.
+        let rooms = rooms
+            .iter()
+            .filter(|(_, member)| member.addr.is_some())
+            .filter_map(|(room_id, _)| self.rooms.get(room_id));
+        for room in rooms {
+            room.addr.do_send(Evict {
+                client_id: msg.client_id,
+                reason: CloseReason {
+                    code: CloseCode::Other(TOO_SLOW_CLOSE_CODE),
+                    description: Some("Too slow to keep up with the chat".to_string()),
+                },
+            });
         }
     }
 }
 
-impl Handler<Typing> for Lobby {
+impl Handler<Direct> for Lobby {
+    type Result = ();
+
+    fn handle(&mut self, msg: Direct, ctx: &mut Context<Self>) {
+        let sender_addr = match self.find_socket(&msg.id) {
+            Some(addr) => addr.clone(),
+            None => return,
+        };
+        let send_error = |error: OutputError| {
+            let _ = sender_addr.try_send(WsMessage::Text(Output::Error(error).to_text()));
+        };
+
+        // Clients still waiting for a seat can't send direct messages.
+        let sender = match self.find_connected_user(&msg.id) {
+            Some(sender) => sender,
+            None => return send_error(OutputError::NotJoined),
+        };
+
+        let recipient = match self.find_connected_user(&msg.to) {
+            Some(recipient) => recipient,
+            None => return send_error(OutputError::UserNotConnected),
+        };
+
+        let appended = self.history.append(
+            &Conversation::direct(msg.id, msg.to),
+            sender,
+            &msg.body,
+            Utc::now(),
+        );
+        let message_output = match appended {
+            Ok(message_output) => message_output,
+            Err(e) => {
+                println!("Failed to store direct message: {}", e);
+                return send_error(OutputError::Internal);
+            }
+        };
+
+        // Deliver the message to both parties.
+        let message =
+            Output::DirectMessage(DirectMessageOutput::new(recipient, message_output)).to_text();
+        self.send_direct_message(&message, &msg.to, ctx);
+        self.send_direct_message(&message, &msg.id, ctx);
+    }
+}
+
+impl Handler<DirectHistory> for Lobby {
+    type Result = ();
+
+    fn handle(&mut self, msg: DirectHistory, _: &mut Context<Self>) {
+        let conversation = Conversation::direct(msg.id, msg.with);
+        let query = HistoryQuery {
+            before: msg.before,
+            after: msg.after,
+            limit: msg
+                .limit
+                .unwrap_or(self.limits.max_history_page_size)
+                .min(self.limits.max_history_page_size),
+        };
+
+        let output = match self.history.range(&conversation, &query) {
+            Ok(page) => {
+                let cursor = page.cursor(query.after.is_some());
+                Output::History(HistoryOutput::new(Some(msg.with), page.messages, cursor))
+            }
+            Err(e) => {
+                println!("Failed to read the history of {}: {}", conversation, e);
+                Output::Error(OutputError::Internal)
+            }
+        };
+        msg.addr.do_send(WsMessage::Text(output.to_text()));
+    }
+}
+
+impl Handler<CreateRoom> for Lobby {
     type Result = ();
 
-    fn handle(&mut self, msg: Typing, _: &mut Context<Self>) {
-        // Get a mutable reference to the current room.
-        let current_room = self.rooms.get_mut(&msg.room_id).unwrap();
+    fn handle(&mut self, msg: CreateRoom, ctx: &mut Context<Self>) {
+        let addr = msg.addr.clone();
+        let send_error = |error: OutputError| {
+            addr.do_send(WsMessage::Text(Output::Error(error).to_text()));
+        };
+
+        let id = Uuid::new_v4();
+        let creator = msg.id;
+
+        // The client creating the room owns it.
+        let config = RoomConfig {
+            id,
+            name: msg.name,
+            topic: msg.topic,
+            max_clients: msg.max_clients,
+            owners: vec![creator],
+            moderators: Vec::new(),
+            members: Vec::new(),
+            guests: Vec::new(),
+            default_role: Role::default(),
+            lifecycle: msg.lifecycle,
+            password_hash: msg.password_hash,
+            private: msg.private,
+            invite_only: msg.invite_only,
+        };
+
+        // Persistent rooms are never reaped, so every user can only have a few.
+        // They are saved before they start so that they are never running
+        // without their settings being saved.
+        let persistent = msg.lifecycle == Lifecycle::Persistent;
+        if persistent {
+            let created = self
+                .rooms
+                .values()
+                .filter(|room| room.lifecycle == Lifecycle::Persistent)
+                .filter(|room| room.creator == Some(creator))
+                .count();
+            if created >= self.limits.max_persistent_rooms {
+                return send_error(OutputError::TooManyRooms);
+            }
 
-        // Add or remove the client from the typing clients in the room.
-        match msg.status {
-            TypingInput::Started => current_room.add_typing_client(&msg.id),
-            TypingInput::Stopped => {
-                current_room.remove_typing_client(&msg.id);
+            let room = SavedRoom {
+                creator,
+                config: config.clone(),
+            };
+            if let Err(e) = self.history.save_room(&room) {
+                println!("Failed to save room {}: {}", id, e);
+                return send_error(OutputError::Internal);
             }
         }
 
-        // Get the username from the current room.
-        let username = current_room.get_username(&msg.id).unwrap();
+        if let Err(e) = self.start_room(config, Some(creator), ctx) {
+            println!("Failed to start room {}: {}", id, e);
+            if persistent {
+                if let Err(e) = self.history.delete_room(id) {
+                    println!("Failed to delete room {}: {}", id, e);
+                }
+            }
+            return send_error(OutputError::Internal);
+        }
 
-        // Construct the message to send out to all other users.
-        let message = serde_json::to_string(&Output::Typing(TypingOutput::new(
-            msg.status,
-            UserOutput::new(msg.id, username),
-        )))
-        .unwrap();
+        // Private rooms can only be found by their id, which the client needs
+        // to hand out.
+        if let Some(room) = self.rooms.get(&id) {
+            msg.addr.do_send(WsMessage::Text(
+                Output::RoomCreated(RoomCreatedOutput::new(room.summary.clone())).to_text(),
+            ));
+        }
+    }
+}
+
+impl Handler<UpdateRoom> for Lobby {
+    type Result = ();
 
-        // Echo to all other users that the client is typing.
-        self.send_to_everyone_except_self(&msg.room_id, &msg.id, &message);
+    // The room checks that the client owns it.
+    fn handle(&mut self, msg: UpdateRoom, _: &mut Context<Self>) {
+        match self.rooms.get(&msg.room_id) {
+            Some(room) => room.addr.do_send(msg),
+            None => {
+                msg.addr.do_send(WsMessage::Text(
+                    Output::Error(OutputError::RoomNotFound).to_room_text(msg.room_id),
+                ));
+            }
+        }
     }
 }
 
-impl Handler<ClientActorMessage> for Lobby {
+impl Handler<DeleteRoom> for Lobby {
     type Result = ();
 
-    fn handle(&mut self, msg: ClientActorMessage, _: &mut Context<Self>) {
-        // Timestamp for when the message was received.
-        let timestamp: DateTime<Utc> = Utc::now();
-
-        // Get the username from the current room.
-        let username = self
-            .rooms
-            .get(&msg.room_id)
-            .unwrap()
-            .get_username(&msg.id)
-            .unwrap();
-
-        // Construct the message to be sent to all clients in the chat room.
-        let message_output = MessageOutput::new(
-            msg.id,
-            UserOutput::new(msg.id, &username),
-            &msg.msg,
-            timestamp,
-        );
+    // The room checks that the client owns it.
+    fn handle(&mut self, msg: DeleteRoom, _: &mut Context<Self>) {
+        match self.rooms.get(&msg.room_id) {
+            Some(room) => room.addr.do_send(msg),
+            None => {
+                msg.addr.do_send(WsMessage::Text(
+                    Output::Error(OutputError::RoomNotFound).to_room_text(msg.room_id),
+                ));
+            }
+        }
+    }
+}
 
-        // Push the message to the history.
-        self.rooms
-            .get_mut(&msg.room_id)
-            .unwrap()
-            .history
-            .push(message_output.clone());
-
-        // Send the message to all other clients in the chatroom.
-        self.send_to_everyone_except_self(
-            &msg.room_id,
-            &msg.id,
-            &serde_json::to_string(&Output::UserPosted(UserPostedOutput::new(
-                message_output.clone(),
-            )))
-            .unwrap(),
-        );
+impl Handler<RoomDeleted> for Lobby {
+    type Result = ();
 
-        // Send information about the message to the client that sent it.
-        self.send_message(
-            &serde_json::to_string(&Output::Posted(PostedOutput::new(message_output))).unwrap(),
-            &msg.id,
-        );
+    // Forgets a deleted room and everyone who was in it. The room has already
+    // taken them out, if anyone was left.
+    fn handle(&mut self, msg: RoomDeleted, _: &mut Context<Self>) {
+        let room_id = msg.room_id;
+
+        let room = self.rooms.remove(&room_id);
+        self.resume_tokens.retain(|_, seat| seat.room_id != room_id);
+        self.members.retain(|_, rooms| {
+            rooms.remove(&room_id);
+            !rooms.is_empty()
+        });
+
+        if room.is_some_and(|room| !room.summary.access.private) {
+            self.send_to_subscribers(&Output::RoomRemoved(RoomRemovedOutput::new(room_id)));
+        }
+    }
+}
+
+impl Handler<CreateInvite> for Lobby {
+    type Result = ();
+
+    // The room checks that the client may hand out invites.
+    fn handle(&mut self, msg: CreateInvite, _: &mut Context<Self>) {
+        match self.rooms.get(&msg.room_id) {
+            Some(room) => room.addr.do_send(msg),
+            None => {
+                msg.addr.do_send(WsMessage::Text(
+                    Output::Error(OutputError::RoomNotFound).to_room_text(msg.room_id),
+                ));
+            }
+        }
     }
 }
@@ -1,27 +1,54 @@
-mod lobby;
-mod messages;
-mod proto;
-mod rooms;
-mod start_connection;
-mod ws;
-
 use actix::Actor;
+use actix_web::web::Data;
 use actix_web::{App, HttpServer};
-use lobby::Lobby;
-use start_connection::start_connection as start_connection_route;
+use server::config::Config;
+use server::history::HistoryBackend;
+use server::lobby::Lobby;
+use server::rate_limit::IpRateLimits;
+use server::start_connection::start_connection as start_connection_route;
+use std::{io, process};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let chat_server = Lobby::default().start();
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            process::exit(2);
+        }
+    };
+
+    // Chat history is kept in memory unless a database file is given, in which
+    // case it survives restarts.
+    let history = match &config.storage.history_db {
+        Some(path) => {
+            println!("Storing chat history in {}", path.display());
+            HistoryBackend::sqlite(path.clone()).map_err(io::Error::other)?
+        }
+        None => HistoryBackend::Memory(Default::default()),
+    };
+
+    let chat_server = Data::new(
+        Lobby::new(history, &config)
+            .map_err(io::Error::other)?
+            .start(),
+    );
+
+    // Connections from the same address share their rate limits.
+    let ip_rate_limits = Data::new(IpRateLimits::default());
 
-    println!("Server listening on port 8080!");
+    println!("Server listening on {}!", config.bind);
 
+    let bind = config.bind;
+    let config = Data::new(config);
     HttpServer::new(move || {
         App::new()
             .service(start_connection_route)
-            .data(chat_server.clone())
+            .app_data(chat_server.clone())
+            .app_data(config.clone())
+            .app_data(ip_rate_limits.clone())
     })
-    .bind("0.0.0.0:8080")?
+    .bind(bind)?
     .run()
     .await
 }
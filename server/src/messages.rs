@@ -1,34 +1,140 @@
-use crate::proto::TypingInput;
-use actix::prelude::{Message, Recipient};
+use crate::proto::{Lifecycle, MessageId, Role, Room, TypingStatus};
+use crate::rooms::ChatRoom;
+use actix::prelude::{Addr, Message, Recipient};
+use actix_web_actors::ws::CloseReason;
+use bytestring::ByteString;
+use std::net::IpAddr;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::time::Duration;
 use uuid::Uuid;
 
+// Close code sent to a connection whose session was taken over by another
+// connection.
+pub const SESSION_REPLACED_CLOSE_CODE: u16 = 4000;
+
+// Close code sent to a connection that can't keep up with the outputs sent to
+// it.
+pub const TOO_SLOW_CLOSE_CODE: u16 = 4001;
+
+// Counts the outputs that were left out for a connection that couldn't keep up.
+// Every room the connection is in counts towards the same counter.
+pub type DroppedOutputs = Arc<AtomicU64>;
+
 // ChatWebsocket responds to this to pipe it though to the actual client.
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct WsMessage(pub String);
+pub enum WsMessage {
+    // A serialized output to send to the client, shared with every other
+    // client it is sent to.
+    Text(ByteString),
+    // A serialized output telling the client why it was removed from the room
+    // with the given id. The connection stays open, and the client stays in
+    // its other rooms.
+    Removed(Uuid, ByteString),
+    // Closes the connection to the client. The room sending it has already
+    // taken the client out, any other rooms are left as if the connection was
+    // lost.
+    Close(Option<CloseReason>),
+}
 
+// ChatWebsocket sends this when the client connects. The lobby sends it the
+// rooms and keeps it posted about changes to them for as long as it is
+// connected, so that it can join more of them.
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Connect {
     pub addr: Recipient<WsMessage>,
 }
 
-// ChatWebsocket sends this to connect to a lobby.
+// ChatWebsocket sends this when the connection ends, for the lobby to stop
+// posting it about changes to the rooms.
 #[derive(Message)]
 #[rtype(result = "()")]
+pub struct Unsubscribe {
+    pub addr: Recipient<WsMessage>,
+}
+
+// ChatWebsocket sends this to the lobby to find the room it wants to join,
+// which is created if it doesn't exist yet (unless creating rooms by joining
+// them is turned off). The lobby responds with the address of the room, if
+// there is one, and hands the room over from any other connection the client
+// still has.
+#[derive(Message)]
+#[rtype(result = "Option<Addr<ChatRoom>>")]
+pub struct FindRoom {
+    pub addr: Recipient<WsMessage>,
+    pub room_id: Uuid,
+    pub self_id: Uuid,
+    pub username: String,
+    pub max_clients: Option<usize>,
+}
+
+// ChatWebsocket sends this to join a room. The room responds with whether the
+// client was let into the room (or its waiting queue).
+#[derive(Message)]
+#[rtype(result = "bool")]
 pub struct Join {
     pub addr: Recipient<WsMessage>,
-    pub lobby_id: Uuid,
     pub self_id: Uuid,
     pub username: String,
+    pub wait: bool,
+    pub ip: Option<IpAddr>,
+    pub password: Option<String>,
+    pub invite: Option<String>,
+    pub dropped: DroppedOutputs,
 }
 
-// ChatWebsocket sends this to disconnect from a lobby.
+// ChatWebsocket sends this to disconnect from a room. If the client didn't
+// leave on purpose its seat is held for a while so that it can resume the
+// session on a new connection.
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Disconnect {
-    pub room_id: Uuid,
+    pub addr: Recipient<WsMessage>,
+    pub self_id: Uuid,
+    pub resumable: bool,
+}
+
+// ChatWebsocket sends this to the lobby to use a resume token. The lobby
+// responds with the client id, room id and room of the session the token
+// resumes. With `self_id` set, only a token for that client can be used.
+#[derive(Message)]
+#[rtype(result = "Option<(Uuid, Uuid, Addr<ChatRoom>)>")]
+pub struct ClaimSeat {
+    pub token: Uuid,
+    pub self_id: Option<Uuid>,
+}
+
+// ChatWebsocket sends this to a room to take over a session that was
+// disconnected. The room responds with whether the session was resumed.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct Resume {
+    pub addr: Recipient<WsMessage>,
     pub self_id: Uuid,
+    pub last_seen: Option<MessageId>,
+    pub ip: Option<IpAddr>,
+    pub dropped: DroppedOutputs,
+}
+
+// The lobby sends this to a room to remove a client that has joined the room on
+// another connection, or that can't keep up. The client's connection is closed
+// with `reason`.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Evict {
+    pub client_id: Uuid,
+    pub reason: CloseReason,
+}
+
+// Sent to the lobby when a client's queue of outputs is full and an output
+// that can't be dropped had to be sent to it. The lobby has the client's room
+// disconnect it.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SlowClient {
+    pub client_id: Uuid,
 }
 
 // ChatWebsocket sends this when a client indicates that they have started or
@@ -37,15 +143,202 @@ pub struct Disconnect {
 #[rtype(result = "()")]
 pub struct Typing {
     pub id: Uuid,
-    pub room_id: Uuid,
-    pub status: TypingInput,
+    pub status: TypingStatus,
 }
 
-// Client sends this to the lobby for the lobby to echo it out.
+// Client sends this to the room for the room to echo it out.
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct ClientActorMessage {
     pub id: Uuid,
     pub msg: String,
+    pub nonce: Option<String>,
+}
+
+// ChatWebsocket sends this when a client asks for a page of the room history.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct History {
+    pub id: Uuid,
+    pub before: Option<MessageId>,
+    pub after: Option<MessageId>,
+    pub limit: Option<usize>,
+}
+
+// ChatWebsocket sends this to the lobby when a client asks for a page of its
+// direct conversation with another user.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct DirectHistory {
+    pub addr: Recipient<WsMessage>,
+    pub id: Uuid,
+    pub with: Uuid,
+    pub before: Option<MessageId>,
+    pub after: Option<MessageId>,
+    pub limit: Option<usize>,
+}
+
+// ChatWebsocket sends this when a client edits one of its messages.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Edit {
+    pub id: Uuid,
+    pub message_id: MessageId,
+    pub body: String,
+}
+
+// ChatWebsocket sends this when a client deletes one of its messages.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Delete {
+    pub id: Uuid,
+    pub message_id: MessageId,
+}
+
+// What a moderator does to a user.
+pub enum ModerationAction {
+    Kick {
+        reason: Option<String>,
+    },
+    // Without a duration the ban lasts until the user is unbanned.
+    Ban {
+        reason: Option<String>,
+        duration: Option<Duration>,
+        ip: bool,
+    },
+    Unban,
+    // Without a duration the mute lasts until the user is unmuted.
+    Mute {
+        reason: Option<String>,
+        duration: Option<Duration>,
+    },
+    Unmute,
+}
+
+// ChatWebsocket sends this when a client moderates another user in a room.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Moderate {
+    pub id: Uuid,
+    pub user: Uuid,
+    pub action: ModerationAction,
+}
+
+// ChatWebsocket sends this when a client grants a role to another user in its
+// room, or revokes it if `role` is `None`.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Grant {
+    pub id: Uuid,
+    pub user: Uuid,
+    pub role: Option<Role>,
+}
+
+// ChatWebsocket sends this to the lobby when a client sends a direct message to
+// another user.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Direct {
+    pub id: Uuid,
+    pub to: Uuid,
+    pub body: String,
+}
+
+// Where a client is in a room.
+pub enum Place {
+    // Waiting in line for a seat.
+    Waiting {
+        addr: Recipient<WsMessage>,
+    },
+    // Seated, with a token that gives the seat back if the connection is lost.
+    Seated {
+        username: String,
+        addr: Recipient<WsMessage>,
+        resume_token: Uuid,
+    },
+    // Seated, but the connection was lost and the session can still be
+    // resumed.
+    Detached,
+}
+
+// A room sends this to the lobby whenever a client's place in it changes, so
+// that the lobby knows where to find every client. `place` is `None` once the
+// client has left the room.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Placement {
+    pub client_id: Uuid,
+    pub room_id: Uuid,
+    pub place: Option<Place>,
+}
+
+// A room sends this to the lobby whenever the number of connected clients or
+// the details of the room change, for the lobby to list the room.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RoomUpdated {
+    pub room: Room,
+}
+
+// ChatWebsocket sends this to the lobby to create a new room owned by the
+// client.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct CreateRoom {
+    pub addr: Recipient<WsMessage>,
+    pub id: Uuid,
+    pub name: String,
+    pub topic: Option<String>,
+    pub max_clients: Option<usize>,
+    pub lifecycle: Lifecycle,
+    pub password_hash: Option<String>,
+    pub private: bool,
+    pub invite_only: bool,
+}
+
+// ChatWebsocket sends this to the lobby when a client changes the details of a
+// room, and the lobby passes it on to the room. Details that are `None` are
+// left as they are, an empty topic removes the topic and a password hash of
+// `Some(None)` removes the password.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct UpdateRoom {
+    pub addr: Recipient<WsMessage>,
+    pub id: Uuid,
+    pub room_id: Uuid,
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub max_clients: Option<usize>,
+    pub password_hash: Option<Option<String>>,
+    pub private: Option<bool>,
+    pub invite_only: Option<bool>,
+}
+
+// ChatWebsocket sends this to the lobby when a client creates an invite to a
+// room, and the lobby passes it on to the room.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct CreateInvite {
+    pub addr: Recipient<WsMessage>,
+    pub id: Uuid,
+    pub room_id: Uuid,
+    pub expires_in: Option<Duration>,
+}
+
+// ChatWebsocket sends this to the lobby when a client deletes a room, and the
+// lobby passes it on to the room.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct DeleteRoom {
+    pub addr: Recipient<WsMessage>,
+    pub id: Uuid,
+    pub room_id: Uuid,
+}
+
+// A room sends this to the lobby once it has been deleted and has taken everyone
+// out, or once it has been empty for too long.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RoomDeleted {
     pub room_id: Uuid,
 }
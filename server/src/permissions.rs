@@ -0,0 +1,105 @@
+use crate::proto::Role;
+
+/// Things a user may or may not do in a room, depending on their role.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Read the room's messages and page through its history.
+    Read,
+    /// Post messages.
+    Post,
+    /// Let others know they are typing.
+    Type,
+    /// Edit and delete their own messages.
+    ChangeOwn,
+    /// Delete anyone's messages.
+    DeleteAny,
+    /// Kick, ban and mute users, and take bans and mutes back.
+    Moderate,
+    /// Grant roles to and revoke roles from other users.
+    GrantRoles,
+    /// Change the details of the room and delete it.
+    ManageRoom,
+}
+
+impl Role {
+    /// Returns true if users with this role have the permission.
+    pub fn allows(self, permission: Permission) -> bool {
+        let least = match permission {
+            Permission::Read => Role::Guest,
+            Permission::Post | Permission::Type | Permission::ChangeOwn => Role::Member,
+            Permission::DeleteAny | Permission::Moderate | Permission::GrantRoles => {
+                Role::Moderator
+            }
+            Permission::ManageRoom => Role::Owner,
+        };
+        self >= least
+    }
+
+    /// Returns true if users with this role may act on (moderate, or change
+    /// the role of) users with the `other` role. Users only have power over
+    /// users below them, except owners, who have power over each other too.
+    pub fn outranks(self, other: Role) -> bool {
+        self > other || self == Role::Owner
+    }
+
+    /// Returns true if users with this role may hand out the `role`. Users
+    /// can only hand out roles below their own, except owners, who can make
+    /// other users owners too.
+    pub fn can_grant(self, role: Role) -> bool {
+        self.allows(Permission::GrantRoles) && self.outranks(role)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROLES: [Role; 4] = [Role::Guest, Role::Member, Role::Moderator, Role::Owner];
+
+    #[test]
+    fn roles_only_outrank_those_below_them() {
+        for &role in &ROLES {
+            for &other in &ROLES {
+                let expected = other < role || role == Role::Owner;
+                assert_eq!(role.outranks(other), expected, "{:?} {:?}", role, other);
+            }
+        }
+    }
+
+    #[test]
+    fn owners_outrank_each_other() {
+        assert!(Role::Owner.outranks(Role::Owner));
+        assert!(!Role::Moderator.outranks(Role::Moderator));
+    }
+
+    #[test]
+    fn moderators_grant_roles_below_their_own() {
+        assert!(Role::Moderator.can_grant(Role::Guest));
+        assert!(Role::Moderator.can_grant(Role::Member));
+        assert!(!Role::Moderator.can_grant(Role::Moderator));
+        assert!(!Role::Moderator.can_grant(Role::Owner));
+    }
+
+    #[test]
+    fn owners_grant_every_role() {
+        for &role in &ROLES {
+            assert!(Role::Owner.can_grant(role), "{:?}", role);
+        }
+    }
+
+    #[test]
+    fn members_and_guests_grant_nothing() {
+        for &role in &ROLES {
+            assert!(!Role::Member.can_grant(role), "{:?}", role);
+            assert!(!Role::Guest.can_grant(role), "{:?}", role);
+        }
+    }
+
+    #[test]
+    fn guests_can_only_read() {
+        assert!(Role::Guest.allows(Permission::Read));
+        assert!(!Role::Guest.allows(Permission::Post));
+        assert!(!Role::Guest.allows(Permission::Type));
+        assert!(!Role::Guest.allows(Permission::ChangeOwn));
+    }
+}
@@ -1,5 +1,7 @@
+use bytestring::ByteString;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use uuid::Uuid;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -9,13 +11,47 @@ pub struct UserOutput {
     pub name: String,
 }
 
+// Messages are numbered per room in the order they were posted, starting at 1.
+pub type MessageId = u64;
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MessageOutput {
-    pub id: Uuid,
+    pub id: MessageId,
     pub user: UserOutput,
     pub body: String,
     pub created_at: DateTime<Utc>,
+    // When the body was last edited, if ever.
+    pub edited_at: Option<DateTime<Utc>>,
+    // Deleted messages are kept in the history as tombstones without a body.
+    pub deleted: bool,
+}
+
+// What a user may do in a room, from least to most.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    // Can only read along.
+    Guest,
+    #[default]
+    Member,
+    // Can also kick, ban and mute users below them and delete any message.
+    Moderator,
+    // Can do everything, including making other users owners.
+    Owner,
+}
+
+// What happens to a room once everyone has left it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Lifecycle {
+    // Deleted, along with its history, after being empty for a while.
+    #[default]
+    Ephemeral,
+    // Kept until its owner deletes it.
+    Persistent,
 }
 
 // Used to represent a chatroom with connected users.
@@ -24,39 +60,311 @@ pub struct MessageOutput {
 pub struct Room {
     pub id: Uuid,
     pub name: String,
+    pub topic: Option<String>,
+    pub access: Access,
     pub connected_clients: usize,
     pub max_clients: usize,
 }
 
+// Who can find and join a room.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Access {
+    // Left out of the `rooms` output, only clients that know the room's id can
+    // join it.
+    pub private: bool,
+    // Joining takes the room's password (or an invite).
+    pub password: bool,
+    // Joining takes an invite.
+    pub invite_only: bool,
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "type", content = "payload", rename_all = "camelCase")]
 pub enum Input {
     #[serde(rename = "join")]
     Join(JoinInput),
     #[serde(rename = "leave")]
-    Leave,
+    Leave(LeaveInput),
     #[serde(rename = "post")]
     Post(PostInput),
     #[serde(rename = "typing")]
     Typing(TypingInput),
+    #[serde(rename = "history")]
+    History(HistoryInput),
+    #[serde(rename = "resume")]
+    Resume(ResumeInput),
+    #[serde(rename = "edit")]
+    Edit(EditInput),
+    #[serde(rename = "delete")]
+    Delete(DeleteInput),
+    #[serde(rename = "direct")]
+    Direct(DirectInput),
+    #[serde(rename = "kick")]
+    Kick(KickInput),
+    #[serde(rename = "ban")]
+    Ban(BanInput),
+    #[serde(rename = "unban")]
+    Unban(UnbanInput),
+    #[serde(rename = "mute")]
+    Mute(MuteInput),
+    #[serde(rename = "unmute")]
+    Unmute(UnmuteInput),
+    #[serde(rename = "grant")]
+    Grant(GrantInput),
+    #[serde(rename = "revoke")]
+    Revoke(RevokeInput),
+    #[serde(rename = "create-room")]
+    CreateRoom(CreateRoomInput),
+    #[serde(rename = "update-room")]
+    UpdateRoom(UpdateRoomInput),
+    #[serde(rename = "delete-room")]
+    DeleteRoom(DeleteRoomInput),
+    #[serde(rename = "create-invite")]
+    CreateInvite(CreateInviteInput),
 }
 
+// Joins a room, on top of any rooms the client is already in. Joining a room
+// the client is already in joins it afresh.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct JoinInput {
     pub username: String,
     pub room: Uuid,
+    // Wait in a queue for a free seat instead of failing if the room is full.
+    #[serde(default)]
+    pub wait: bool,
+    // Capacity to use if the join creates a new room.
+    #[serde(default)]
+    pub max_clients: Option<usize>,
+    // Needed to join a room with a password, unless an invite is given.
+    #[serde(default)]
+    pub password: Option<String>,
+    // Code of an invite to the room, which can only be used once.
+    #[serde(default)]
+    pub invite: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LeaveInput {
+    pub room: Uuid,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PostInput {
+    pub room: Uuid,
+    pub message: String,
+    // Picked by the client and echoed back in the `posted` output. Posting
+    // again with the same nonce doesn't post the message twice, so that posts
+    // can safely be retried.
+    #[serde(default)]
+    pub nonce: Option<String>,
+}
+
+// Asks for a page of a room's history. With `after` set the page
+// continues forwards from that message, otherwise it goes backwards from
+// `before` (or from the most recent message).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryInput {
+    // Not needed with `with`.
+    #[serde(default)]
+    pub room: Option<Uuid>,
+    #[serde(default)]
+    pub before: Option<MessageId>,
+    #[serde(default)]
+    pub after: Option<MessageId>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    // Pages through the direct conversation with this user instead, which
+    // doesn't take a seat in any room.
+    #[serde(default)]
+    pub with: Option<Uuid>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EditInput {
+    pub room: Uuid,
+    pub id: MessageId,
     pub message: String,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub enum TypingInput {
+pub struct DeleteInput {
+    pub room: Uuid,
+    pub id: MessageId,
+}
+
+// A private message to a single connected user, in any room.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectInput {
+    pub to: Uuid,
+    pub message: String,
+}
+
+// Removes a user from a room. Only moderators can kick users.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KickInput {
+    pub room: Uuid,
+    pub user: Uuid,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+// Removes a user from a room and keeps them from joining it again,
+// for `duration` seconds or until they are unbanned. With `ip` set, the address
+// the user is connected from is banned as well, which takes the user to be
+// connected.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BanInput {
+    pub room: Uuid,
+    pub user: Uuid,
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub duration: Option<u64>,
+    #[serde(default)]
+    pub ip: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnbanInput {
+    pub room: Uuid,
+    pub user: Uuid,
+}
+
+// Keeps a user from posting, typing and editing in a room, for
+// `duration` seconds or until they are unmuted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MuteInput {
+    pub room: Uuid,
+    pub user: Uuid,
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub duration: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnmuteInput {
+    pub room: Uuid,
+    pub user: Uuid,
+}
+
+// Gives a user a role in a room. Users can only grant roles below
+// their own (owners can grant any role) to users below them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GrantInput {
+    pub room: Uuid,
+    pub user: Uuid,
+    pub role: Role,
+}
+
+// Puts a user back to the default role of a room.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RevokeInput {
+    pub room: Uuid,
+    pub user: Uuid,
+}
+
+// Creates a new room owned by the client. The client still has to join it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateRoomInput {
+    pub name: String,
+    #[serde(default)]
+    pub topic: Option<String>,
+    // Capacity of the room, the server's default if not given.
+    #[serde(default)]
+    pub max_clients: Option<usize>,
+    #[serde(default)]
+    pub lifecycle: Lifecycle,
+    // Password needed to join the room.
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub private: bool,
+    #[serde(default)]
+    pub invite_only: bool,
+}
+
+// Changes the details of a room the client owns. Details that aren't given
+// are left as they are, and an empty topic or password removes the topic or
+// password.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateRoomInput {
+    pub room: Uuid,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub topic: Option<String>,
+    #[serde(default)]
+    pub max_clients: Option<usize>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub private: Option<bool>,
+    #[serde(default)]
+    pub invite_only: Option<bool>,
+}
+
+// Creates an invite to a room the client moderates, which lets one user join
+// the room without its password. Without `expires_in` (seconds) the invite is
+// good until it is used.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateInviteInput {
+    pub room: Uuid,
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+}
+
+// Deletes a room the client owns, along with its history. Everyone in the
+// room is taken out of it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteRoomInput {
+    pub room: Uuid,
+}
+
+// Reattaches a connection to a seat in a room that was disconnected, using
+// the resume token handed out when the seat was taken. A connection that is
+// signed in, or already in other rooms, can only resume seats of its own
+// session.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResumeInput {
+    pub token: Uuid,
+    // Id of the last message the client received, everything posted after it
+    // is replayed. Edits and deletions of messages up to it are not, refetch
+    // them with a history input.
+    #[serde(default)]
+    pub last_seen: Option<MessageId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TypingInput {
+    pub room: Uuid,
+    pub status: TypingStatus,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TypingStatus {
     #[serde(rename = "started")]
     Started,
     #[serde(rename = "stopped")]
@@ -72,6 +380,10 @@ pub enum Output {
     Rooms(RoomsOutput),
     #[serde(rename = "joined")]
     Joined(JoinedOutput),
+    #[serde(rename = "resumed")]
+    Resumed(ResumedOutput),
+    #[serde(rename = "queued")]
+    Queued(QueuedOutput),
     #[serde(rename = "user-joined")]
     UserJoined(UserJoinedOutput),
     #[serde(rename = "user-left")]
@@ -80,8 +392,43 @@ pub enum Output {
     Posted(PostedOutput),
     #[serde(rename = "user-posted")]
     UserPosted(UserPostedOutput),
+    #[serde(rename = "message-edited")]
+    MessageEdited(MessageEditedOutput),
+    #[serde(rename = "message-deleted")]
+    MessageDeleted(MessageDeletedOutput),
+    #[serde(rename = "direct-message")]
+    DirectMessage(DirectMessageOutput),
     #[serde(rename = "user-typing")]
     Typing(TypingOutput),
+    #[serde(rename = "history")]
+    History(HistoryOutput),
+    #[serde(rename = "moderated")]
+    Moderated(ModeratedOutput),
+    #[serde(rename = "role-changed")]
+    RoleChanged(RoleChangedOutput),
+    #[serde(rename = "room-created")]
+    RoomCreated(RoomCreatedOutput),
+    #[serde(rename = "room-updated")]
+    RoomUpdated(RoomUpdatedOutput),
+    #[serde(rename = "room-deleted")]
+    RoomDeleted(RoomDeletedOutput),
+    #[serde(rename = "room-added")]
+    RoomAdded(RoomAddedOutput),
+    #[serde(rename = "room-removed")]
+    RoomRemoved(RoomRemovedOutput),
+    #[serde(rename = "invite-created")]
+    InviteCreated(InviteCreatedOutput),
+    #[serde(rename = "outputs-dropped")]
+    OutputsDropped(OutputsDroppedOutput),
+}
+
+// An output sent by a room, with the id of the room next to its type and
+// payload.
+#[derive(Debug, Serialize)]
+pub struct RoomOutput<'a> {
+    #[serde(flatten)]
+    pub output: &'a Output,
+    pub room: Uuid,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
@@ -95,6 +442,37 @@ pub enum OutputError {
     NotJoined,
     #[serde(rename = "invalid-message-body")]
     InvalidMessageBody,
+    #[serde(rename = "invalid-input")]
+    InvalidInput,
+    #[serde(rename = "room-full")]
+    RoomFull,
+    #[serde(rename = "message-not-found")]
+    MessageNotFound,
+    #[serde(rename = "internal-error")]
+    Internal,
+    #[serde(rename = "invalid-resume-token")]
+    InvalidResumeToken,
+    #[serde(rename = "forbidden")]
+    Forbidden,
+    #[serde(rename = "user-not-connected")]
+    UserNotConnected,
+    // The input was dropped because the client is sending too fast. The client
+    // may send it again after `retry_after` milliseconds.
+    #[serde(rename = "rate-limited", rename_all = "camelCase")]
+    RateLimited { retry_after: u64 },
+    #[serde(rename = "banned")]
+    Banned,
+    #[serde(rename = "muted")]
+    Muted,
+    #[serde(rename = "room-not-found")]
+    RoomNotFound,
+    // The password or invite the client tried to join a room with is wrong
+    // (or missing).
+    #[serde(rename = "bad-credentials")]
+    BadCredentials,
+    // The client already has as many persistent rooms as it may create.
+    #[serde(rename = "too-many-rooms")]
+    TooManyRooms,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -108,8 +486,48 @@ pub struct RoomsOutput {
 pub struct JoinedOutput {
     pub user: UserOutput,
     pub others: Vec<UserOutput>,
+    // The most recent messages of the room.
     pub messages: Vec<MessageOutput>,
+    // Set if there are older messages, pass it as `before` in a history input
+    // to get them.
+    pub cursor: Option<MessageId>,
     pub typing: Vec<UserOutput>,
+    pub roles: RolesOutput,
+    // Lets the client resume the session if the connection drops.
+    pub resume_token: Uuid,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResumedOutput {
+    pub user: UserOutput,
+    pub others: Vec<UserOutput>,
+    // Messages posted since the last message the client has seen.
+    pub messages: Vec<MessageOutput>,
+    // Set if even more messages were posted, pass it as `after` in a history
+    // input to get them.
+    pub cursor: Option<MessageId>,
+    pub typing: Vec<UserOutput>,
+    pub roles: RolesOutput,
+    // Replaces the token that was used to resume the session.
+    pub resume_token: Uuid,
+}
+
+// The roles in a room. Users that aren't listed have the default role.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RolesOutput {
+    pub default: Role,
+    pub users: HashMap<Uuid, Role>,
+}
+
+// Sent to a client waiting for a seat in a full room. Position 1 is next in
+// line.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuedOutput {
+    pub room: Uuid,
+    pub position: usize,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -128,6 +546,8 @@ pub struct UserLeftOutput {
 #[serde(rename_all = "camelCase")]
 pub struct PostedOutput {
     pub message: MessageOutput,
+    // The nonce the message was posted with, if any.
+    pub nonce: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -136,13 +556,137 @@ pub struct UserPostedOutput {
     pub message: MessageOutput,
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageEditedOutput {
+    pub message: MessageOutput,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageDeletedOutput {
+    pub message: MessageOutput,
+}
+
+// Sent to both the sender and the recipient of a direct message. The sender is
+// the user of the message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectMessageOutput {
+    pub to: UserOutput,
+    pub message: MessageOutput,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryOutput {
+    // The user whose direct conversation this is a page of, if it isn't the
+    // room's history.
+    pub with: Option<Uuid>,
+    pub messages: Vec<MessageOutput>,
+    // Set if there are more messages in the direction that was paged in, pass
+    // it back in the same field of the next history input to get them.
+    pub cursor: Option<MessageId>,
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TypingOutput {
-    pub status: TypingInput,
+    pub status: TypingStatus,
     pub user: UserOutput,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Moderation {
+    Kicked,
+    Banned,
+    Unbanned,
+    Muted,
+    Unmuted,
+}
+
+// Sent to the client that created a room.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomCreatedOutput {
+    pub room: Room,
+}
+
+// Sent to everyone in a room, and to the owner who changed it, when the
+// details of the room change. Every client gets it, without a room tag,
+// whenever anything the `rooms` output lists about a room changes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomUpdatedOutput {
+    pub room: Room,
+}
+
+// Sent to everyone in a room, and to the owner who deleted it, when the room
+// is deleted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomDeletedOutput {
+    pub room: Uuid,
+}
+
+// Sent to every client when a room is added to the `rooms` they were sent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomAddedOutput {
+    pub room: Room,
+}
+
+// Sent to every client when a room is removed from the `rooms` they were
+// sent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomRemovedOutput {
+    pub room: Uuid,
+}
+
+// Sent to the client that created an invite.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InviteCreatedOutput {
+    pub room: Uuid,
+    pub code: String,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+// Sent to a client that couldn't keep up, on the next heartbeat after outputs
+// were left out for it. `count` is how many outputs were left out on the
+// connection so far. Only typing events are left out, so the client may want
+// to stop showing users as typing.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputsDroppedOutput {
+    pub count: u64,
+}
+
+// Sent to everyone in the room when a user's role changes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoleChangedOutput {
+    pub user: Uuid,
+    pub role: Role,
+    pub by: UserOutput,
+}
+
+// Sent to everyone in the room, and to the user it was done to, when a
+// moderator kicks, bans or mutes a user (or takes a ban or mute back).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModeratedOutput {
+    pub action: Moderation,
+    // Id of the user the moderator acted on.
+    pub user: Uuid,
+    pub by: UserOutput,
+    pub reason: Option<String>,
+    // When the ban or mute ends, if it ever does.
+    pub until: Option<DateTime<Utc>>,
+}
+
 impl UserOutput {
     pub fn new(id: Uuid, name: &str) -> Self {
         UserOutput {
@@ -153,21 +697,69 @@ impl UserOutput {
 }
 
 impl MessageOutput {
-    pub fn new(id: Uuid, user: UserOutput, body: &str, created_at: DateTime<Utc>) -> Self {
+    pub fn new(id: MessageId, user: UserOutput, body: &str, created_at: DateTime<Utc>) -> Self {
         MessageOutput {
             id,
             user,
             body: String::from(body),
             created_at,
+            edited_at: None,
+            deleted: false,
         }
     }
+
+    /// Replaces the body of the message.
+    pub fn edit(&mut self, body: &str, edited_at: DateTime<Utc>) {
+        self.body = String::from(body);
+        self.edited_at = Some(edited_at);
+    }
+
+    /// Turns the message into a tombstone.
+    pub fn delete(&mut self) {
+        self.body.clear();
+        self.deleted = true;
+    }
+}
+
+impl Output {
+    /// Serializes the output once, into text that can be shared by every
+    /// client it is sent to. Cloning the text only bumps a reference count,
+    /// and it is written to every client's socket as it is.
+    pub fn to_text(&self) -> ByteString {
+        // Outputs only hold strings, numbers and ids, which always serialize.
+        serde_json::to_string(self).unwrap().into()
+    }
+
+    /// Like `to_text`, for an output that belongs to a room, which is tagged
+    /// with the room's id so that clients in several rooms can tell them
+    /// apart.
+    pub fn to_room_text(&self, room: Uuid) -> ByteString {
+        serde_json::to_string(&RoomOutput { output: self, room })
+            .unwrap()
+            .into()
+    }
+
+    /// Returns true if the output can be left out for a client that can't keep
+    /// up, without leaving the client in a state it can't recover from.
+    pub fn is_droppable(&self) -> bool {
+        matches!(self, Output::Typing(_))
+    }
 }
 
 impl Room {
-    pub fn new(id: Uuid, name: String, connected_clients: usize, max_clients: usize) -> Self {
+    pub fn new(
+        id: Uuid,
+        name: String,
+        topic: Option<String>,
+        access: Access,
+        connected_clients: usize,
+        max_clients: usize,
+    ) -> Self {
         Room {
             id,
             name,
+            topic,
+            access,
             connected_clients,
             max_clients,
         }
@@ -185,17 +777,51 @@ impl JoinedOutput {
         user: UserOutput,
         others: Vec<UserOutput>,
         messages: Vec<MessageOutput>,
+        cursor: Option<MessageId>,
         typing: Vec<UserOutput>,
+        roles: RolesOutput,
+        resume_token: Uuid,
     ) -> Self {
         JoinedOutput {
             user,
             others,
             messages,
+            cursor,
             typing,
+            roles,
+            resume_token,
         }
     }
 }
 
+impl ResumedOutput {
+    pub fn new(
+        user: UserOutput,
+        others: Vec<UserOutput>,
+        messages: Vec<MessageOutput>,
+        cursor: Option<MessageId>,
+        typing: Vec<UserOutput>,
+        roles: RolesOutput,
+        resume_token: Uuid,
+    ) -> Self {
+        ResumedOutput {
+            user,
+            others,
+            messages,
+            cursor,
+            typing,
+            roles,
+            resume_token,
+        }
+    }
+}
+
+impl QueuedOutput {
+    pub fn new(room: Uuid, position: usize) -> Self {
+        QueuedOutput { room, position }
+    }
+}
+
 impl UserJoinedOutput {
     pub fn new(user: UserOutput) -> Self {
         UserJoinedOutput { user }
@@ -203,7 +829,7 @@ impl UserJoinedOutput {
 }
 
 impl UserLeftOutput {
-    pub fn new(user_id: Uuid, username: &String) -> Self {
+    pub fn new(user_id: Uuid, username: &str) -> Self {
         UserLeftOutput {
             user: UserOutput::new(user_id, username),
         }
@@ -211,8 +837,8 @@ impl UserLeftOutput {
 }
 
 impl PostedOutput {
-    pub fn new(message: MessageOutput) -> Self {
-        PostedOutput { message }
+    pub fn new(message: MessageOutput, nonce: Option<String>) -> Self {
+        PostedOutput { message, nonce }
     }
 }
 
@@ -222,8 +848,116 @@ impl UserPostedOutput {
     }
 }
 
+impl MessageEditedOutput {
+    pub fn new(message: MessageOutput) -> Self {
+        MessageEditedOutput { message }
+    }
+}
+
+impl MessageDeletedOutput {
+    pub fn new(message: MessageOutput) -> Self {
+        MessageDeletedOutput { message }
+    }
+}
+
+impl DirectMessageOutput {
+    pub fn new(to: UserOutput, message: MessageOutput) -> Self {
+        DirectMessageOutput { to, message }
+    }
+}
+
+impl HistoryOutput {
+    pub fn new(
+        with: Option<Uuid>,
+        messages: Vec<MessageOutput>,
+        cursor: Option<MessageId>,
+    ) -> Self {
+        HistoryOutput {
+            with,
+            messages,
+            cursor,
+        }
+    }
+}
+
 impl TypingOutput {
-    pub fn new(status: TypingInput, user: UserOutput) -> Self {
+    pub fn new(status: TypingStatus, user: UserOutput) -> Self {
         TypingOutput { status, user }
     }
 }
+
+impl ModeratedOutput {
+    pub fn new(
+        action: Moderation,
+        user: Uuid,
+        by: UserOutput,
+        reason: Option<String>,
+        until: Option<DateTime<Utc>>,
+    ) -> Self {
+        ModeratedOutput {
+            action,
+            user,
+            by,
+            reason,
+            until,
+        }
+    }
+}
+
+impl RolesOutput {
+    pub fn new(default: Role, users: HashMap<Uuid, Role>) -> Self {
+        RolesOutput { default, users }
+    }
+}
+
+impl RoleChangedOutput {
+    pub fn new(user: Uuid, role: Role, by: UserOutput) -> Self {
+        RoleChangedOutput { user, role, by }
+    }
+}
+
+impl RoomCreatedOutput {
+    pub fn new(room: Room) -> Self {
+        RoomCreatedOutput { room }
+    }
+}
+
+impl RoomUpdatedOutput {
+    pub fn new(room: Room) -> Self {
+        RoomUpdatedOutput { room }
+    }
+}
+
+impl RoomDeletedOutput {
+    pub fn new(room: Uuid) -> Self {
+        RoomDeletedOutput { room }
+    }
+}
+
+impl RoomAddedOutput {
+    pub fn new(room: Room) -> Self {
+        RoomAddedOutput { room }
+    }
+}
+
+impl RoomRemovedOutput {
+    pub fn new(room: Uuid) -> Self {
+        RoomRemovedOutput { room }
+    }
+}
+
+impl OutputsDroppedOutput {
+    pub fn new(count: u64) -> Self {
+        OutputsDroppedOutput { count }
+    }
+}
+
+impl InviteCreatedOutput {
+    pub fn new(room: Uuid, code: String, expires_at: Option<DateTime<Utc>>) -> Self {
+        InviteCreatedOutput {
+            room,
+            code,
+            expires_at,
+        }
+    }
+}
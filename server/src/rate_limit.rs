@@ -0,0 +1,527 @@
+use crate::config::seconds;
+use crate::proto::Input;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// How many inputs of a kind a client may send. Every input takes a token from
+/// a bucket that holds up to `burst` tokens and refills at `per_second`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Rate {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+impl Rate {
+    const fn new(burst: u32, per_second: f64) -> Self {
+        Rate { burst, per_second }
+    }
+}
+
+/// Limits on one kind of input, for every connection on its own and for all
+/// connections from the same IP address together.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct KindLimit {
+    pub connection: Rate,
+    pub ip: Rate,
+}
+
+impl KindLimit {
+    const fn new(connection: Rate, ip: Rate) -> Self {
+        KindLimit { connection, ip }
+    }
+}
+
+/// How fast clients may send inputs, and what happens to clients that keep
+/// sending them faster.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RateLimitConfig {
+    pub join: KindLimit,
+    pub leave: KindLimit,
+    pub post: KindLimit,
+    pub typing: KindLimit,
+    pub history: KindLimit,
+    pub resume: KindLimit,
+    pub edit: KindLimit,
+    pub delete: KindLimit,
+    pub direct: KindLimit,
+    pub moderate: KindLimit,
+    pub room: KindLimit,
+
+    /// How many rate-limited inputs a connection gets away with before it is
+    /// muted.
+    pub strikes: Rate,
+
+    /// How long a muted connection can't post, type, edit, delete or send
+    /// direct messages (seconds).
+    #[serde(deserialize_with = "seconds")]
+    pub mute_duration: Duration,
+
+    /// How many times a connection is muted before it is disconnected instead.
+    pub mutes_before_disconnect: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        let chatty = KindLimit::new(Rate::new(5, 1.0), Rate::new(20, 5.0));
+        let browsing = KindLimit::new(Rate::new(10, 2.0), Rate::new(40, 10.0));
+        let moving = KindLimit::new(Rate::new(5, 0.5), Rate::new(20, 2.0));
+
+        RateLimitConfig {
+            join: moving,
+            leave: moving,
+            post: chatty,
+            typing: browsing,
+            history: browsing,
+            resume: KindLimit::new(Rate::new(3, 0.2), Rate::new(10, 1.0)),
+            edit: chatty,
+            delete: chatty,
+            direct: chatty,
+            moderate: chatty,
+            room: KindLimit::new(Rate::new(5, 0.1), Rate::new(20, 0.5)),
+            strikes: Rate::new(20, 0.5),
+            mute_duration: Duration::from_secs(30),
+            mutes_before_disconnect: 2,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    /// Returns the limits on one kind of input.
+    pub fn limit(&self, kind: InputKind) -> &KindLimit {
+        match kind {
+            InputKind::Join => &self.join,
+            InputKind::Leave => &self.leave,
+            InputKind::Post => &self.post,
+            InputKind::Typing => &self.typing,
+            InputKind::History => &self.history,
+            InputKind::Resume => &self.resume,
+            InputKind::Edit => &self.edit,
+            InputKind::Delete => &self.delete,
+            InputKind::Direct => &self.direct,
+            InputKind::Moderate => &self.moderate,
+            InputKind::Room => &self.room,
+        }
+    }
+
+    /// Returns every rate in the configuration along with its name, for
+    /// validation.
+    pub fn rates(&self) -> Vec<(String, Rate)> {
+        let mut rates = vec![("strikes".to_string(), self.strikes)];
+        for &kind in InputKind::ALL.iter() {
+            let limit = self.limit(kind);
+            rates.push((format!("{}.connection", kind.name()), limit.connection));
+            rates.push((format!("{}.ip", kind.name()), limit.ip));
+        }
+        rates
+    }
+}
+
+/// The kinds of input that are rate limited separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InputKind {
+    Join,
+    Leave,
+    Post,
+    Typing,
+    History,
+    Resume,
+    Edit,
+    Delete,
+    Direct,
+    // Kicking, banning and muting users, taking bans and mutes back, and
+    // granting and revoking roles.
+    Moderate,
+    // Creating, updating and deleting rooms.
+    Room,
+}
+
+impl InputKind {
+    const ALL: [InputKind; 11] = [
+        InputKind::Join,
+        InputKind::Leave,
+        InputKind::Post,
+        InputKind::Typing,
+        InputKind::History,
+        InputKind::Resume,
+        InputKind::Edit,
+        InputKind::Delete,
+        InputKind::Direct,
+        InputKind::Moderate,
+        InputKind::Room,
+    ];
+
+    pub fn of(input: &Input) -> Self {
+        match input {
+            Input::Join(_) => InputKind::Join,
+            Input::Leave(_) => InputKind::Leave,
+            Input::Post(_) => InputKind::Post,
+            Input::Typing(_) => InputKind::Typing,
+            Input::History(_) => InputKind::History,
+            Input::Resume(_) => InputKind::Resume,
+            Input::Edit(_) => InputKind::Edit,
+            Input::Delete(_) => InputKind::Delete,
+            Input::Direct(_) => InputKind::Direct,
+            Input::Kick(_)
+            | Input::Ban(_)
+            | Input::Unban(_)
+            | Input::Mute(_)
+            | Input::Unmute(_)
+            | Input::Grant(_)
+            | Input::Revoke(_) => InputKind::Moderate,
+            Input::CreateRoom(_)
+            | Input::UpdateRoom(_)
+            | Input::DeleteRoom(_)
+            | Input::CreateInvite(_) => InputKind::Room,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            InputKind::Join => "join",
+            InputKind::Leave => "leave",
+            InputKind::Post => "post",
+            InputKind::Typing => "typing",
+            InputKind::History => "history",
+            InputKind::Resume => "resume",
+            InputKind::Edit => "edit",
+            InputKind::Delete => "delete",
+            InputKind::Direct => "direct",
+            InputKind::Moderate => "moderate",
+            InputKind::Room => "room",
+        }
+    }
+
+    // Whether a muted client is kept from sending this kind of input, which is
+    // everything that other clients get to see.
+    fn is_muted(self) -> bool {
+        matches!(
+            self,
+            InputKind::Post
+                | InputKind::Typing
+                | InputKind::Edit
+                | InputKind::Delete
+                | InputKind::Direct
+        )
+    }
+}
+
+/// A bucket of tokens that refills at a steady rate.
+// Longest a client is told to wait for a token. Rates slow enough to take
+// longer than that don't overflow the wait.
+const MAX_WAIT: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(rate: &Rate, now: Instant) -> Self {
+        TokenBucket {
+            tokens: f64::from(rate.burst),
+            updated: now,
+        }
+    }
+
+    // Takes a token from the bucket, or returns how long it takes until the
+    // bucket has a token again.
+    fn take(&mut self, rate: &Rate, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.per_second).min(f64::from(rate.burst));
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - self.tokens) / rate.per_second;
+            Err(Duration::try_from_secs_f64(wait).map_or(MAX_WAIT, |wait| wait.min(MAX_WAIT)))
+        }
+    }
+}
+
+/// The buckets of every IP address that has connections open, shared by all
+/// connections. An address is forgotten once its last connection closes.
+#[derive(Clone, Default)]
+pub struct IpRateLimits(Arc<Mutex<HashMap<IpAddr, IpBuckets>>>);
+
+#[derive(Default)]
+struct IpBuckets {
+    connections: usize,
+    buckets: HashMap<InputKind, TokenBucket>,
+}
+
+impl IpRateLimits {
+    // A panicking connection can't leave the buckets in a state that matters,
+    // so a poisoned lock is used anyway.
+    fn with<T>(&self, f: impl FnOnce(&mut HashMap<IpAddr, IpBuckets>) -> T) -> T {
+        f(&mut self.0.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn connect(&self, ip: IpAddr) {
+        self.with(|ips| ips.entry(ip).or_default().connections += 1);
+    }
+
+    fn disconnect(&self, ip: IpAddr) {
+        self.with(|ips| {
+            if let Some(entry) = ips.get_mut(&ip) {
+                entry.connections -= 1;
+                if entry.connections == 0 {
+                    ips.remove(&ip);
+                }
+            }
+        });
+    }
+
+    fn take(&self, ip: IpAddr, kind: InputKind, rate: &Rate, now: Instant) -> Result<(), Duration> {
+        self.with(|ips| {
+            ips.entry(ip)
+                .or_default()
+                .buckets
+                .entry(kind)
+                .or_insert_with(|| TokenBucket::full(rate, now))
+                .take(rate, now)
+        })
+    }
+}
+
+/// What a connection may do with an input it received.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    /// The input can be handled.
+    Allowed,
+    /// The input is dropped. The client may try again after the given time.
+    Limited(Duration),
+    /// The client has sent too many inputs too fast for too long and is
+    /// disconnected.
+    Disconnect,
+}
+
+/// Keeps track of how fast a single connection sends inputs. Clients that keep
+/// getting rate limited are muted for a while, and disconnected if they still
+/// don't slow down.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: HashMap<InputKind, TokenBucket>,
+    ip: Option<(IpAddr, IpRateLimits)>,
+    strikes: TokenBucket,
+    muted_until: Option<Instant>,
+    mutes: u32,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig, ip: Option<IpAddr>, ip_limits: IpRateLimits) -> Self {
+        if let Some(ip) = ip {
+            ip_limits.connect(ip);
+        }
+
+        RateLimiter {
+            strikes: TokenBucket::full(&config.strikes, Instant::now()),
+            config,
+            buckets: HashMap::new(),
+            ip: ip.map(|ip| (ip, ip_limits)),
+            muted_until: None,
+            mutes: 0,
+        }
+    }
+
+    /// Decides what to do with an input of the given kind received now.
+    pub fn check(&mut self, kind: InputKind, now: Instant) -> Verdict {
+        if kind.is_muted() {
+            if let Some(until) = self.muted_until.filter(|until| *until > now) {
+                return self.strike(until - now, now);
+            }
+        }
+
+        let limit = *self.config.limit(kind);
+
+        let taken = self
+            .buckets
+            .entry(kind)
+            .or_insert_with(|| TokenBucket::full(&limit.connection, now))
+            .take(&limit.connection, now);
+        if let Err(retry_after) = taken {
+            return self.strike(retry_after, now);
+        }
+
+        if let Some((ip, ip_limits)) = &self.ip {
+            if let Err(retry_after) = ip_limits.take(*ip, kind, &limit.ip, now) {
+                return self.strike(retry_after, now);
+            }
+        }
+
+        Verdict::Allowed
+    }
+
+    // Counts a rate-limited input against the client, muting or disconnecting
+    // it once it has run out of strikes.
+    fn strike(&mut self, retry_after: Duration, now: Instant) -> Verdict {
+        if self.strikes.take(&self.config.strikes, now).is_ok() {
+            return Verdict::Limited(retry_after);
+        }
+
+        if self.mutes >= self.config.mutes_before_disconnect {
+            return Verdict::Disconnect;
+        }
+
+        self.mutes += 1;
+        self.muted_until = Some(now + self.config.mute_duration);
+        self.strikes = TokenBucket::full(&self.config.strikes, now);
+
+        Verdict::Limited(self.config.mute_duration.max(retry_after))
+    }
+}
+
+impl Drop for RateLimiter {
+    fn drop(&mut self) {
+        if let Some((ip, ip_limits)) = &self.ip {
+            ip_limits.disconnect(*ip);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    // Two posts a second with a burst of two per connection, three in a burst
+    // per address. Two strikes per mute, and a disconnect on the second mute.
+    fn config() -> RateLimitConfig {
+        RateLimitConfig {
+            post: KindLimit::new(Rate::new(2, 1.0), Rate::new(3, 1.0)),
+            strikes: Rate::new(2, 0.001),
+            mute_duration: Duration::from_secs(30),
+            mutes_before_disconnect: 1,
+            ..RateLimitConfig::default()
+        }
+    }
+
+    fn limiter(ip: Option<IpAddr>, ip_limits: &IpRateLimits) -> RateLimiter {
+        RateLimiter::new(config(), ip, ip_limits.clone())
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn bucket_refills_at_its_rate() {
+        let rate = Rate::new(2, 0.5);
+        let start = Instant::now();
+        let mut bucket = TokenBucket::full(&rate, start);
+
+        assert_eq!(bucket.take(&rate, start), Ok(()));
+        assert_eq!(bucket.take(&rate, start), Ok(()));
+        assert_eq!(bucket.take(&rate, start), Err(secs(2)));
+        assert_eq!(bucket.take(&rate, start + secs(1)), Err(secs(1)));
+        assert_eq!(bucket.take(&rate, start + secs(2)), Ok(()));
+
+        // The bucket never holds more than its burst.
+        let later = start + secs(60);
+        assert_eq!(bucket.take(&rate, later), Ok(()));
+        assert_eq!(bucket.take(&rate, later), Ok(()));
+        assert_eq!(bucket.take(&rate, later), Err(secs(2)));
+    }
+
+    #[test]
+    fn waits_for_very_slow_rates_are_capped() {
+        let rate = Rate::new(1, f64::MIN_POSITIVE);
+        let start = Instant::now();
+        let mut bucket = TokenBucket::full(&rate, start);
+
+        assert_eq!(bucket.take(&rate, start), Ok(()));
+        assert_eq!(bucket.take(&rate, start), Err(MAX_WAIT));
+    }
+
+    #[test]
+    fn strikes_lead_to_a_mute_then_a_disconnect() {
+        let mut limiter = limiter(None, &IpRateLimits::default());
+        let start = Instant::now();
+
+        assert_eq!(limiter.check(InputKind::Post, start), Verdict::Allowed);
+        assert_eq!(limiter.check(InputKind::Post, start), Verdict::Allowed);
+
+        // Two strikes.
+        assert_eq!(
+            limiter.check(InputKind::Post, start),
+            Verdict::Limited(secs(1))
+        );
+        assert_eq!(
+            limiter.check(InputKind::Post, start),
+            Verdict::Limited(secs(1))
+        );
+
+        // Out of strikes, so muted.
+        assert_eq!(
+            limiter.check(InputKind::Post, start),
+            Verdict::Limited(secs(30))
+        );
+
+        // Muted inputs are refused even with tokens left, but still count as
+        // strikes. Other kinds of input are not muted.
+        let muted = start + secs(10);
+        assert_eq!(limiter.check(InputKind::Join, muted), Verdict::Allowed);
+        assert_eq!(
+            limiter.check(InputKind::Post, muted),
+            Verdict::Limited(secs(20))
+        );
+        assert_eq!(
+            limiter.check(InputKind::Post, muted),
+            Verdict::Limited(secs(20))
+        );
+
+        // Out of strikes again, with no mutes left.
+        assert_eq!(limiter.check(InputKind::Post, muted), Verdict::Disconnect);
+    }
+
+    #[test]
+    fn mute_ends() {
+        let mut limiter = limiter(None, &IpRateLimits::default());
+        let start = Instant::now();
+
+        for _ in 0..4 {
+            limiter.check(InputKind::Post, start);
+        }
+        assert_eq!(
+            limiter.check(InputKind::Post, start),
+            Verdict::Limited(secs(30))
+        );
+
+        let unmuted = start + secs(30);
+        assert_eq!(limiter.check(InputKind::Post, unmuted), Verdict::Allowed);
+    }
+
+    #[test]
+    fn connections_from_an_address_share_its_limit() {
+        let ip_limits = IpRateLimits::default();
+        let mut first = limiter(Some(IP), &ip_limits);
+        let mut second = limiter(Some(IP), &ip_limits);
+        let mut elsewhere = limiter(None, &ip_limits);
+        let start = Instant::now();
+
+        assert_eq!(first.check(InputKind::Post, start), Verdict::Allowed);
+        assert_eq!(first.check(InputKind::Post, start), Verdict::Allowed);
+        assert_eq!(second.check(InputKind::Post, start), Verdict::Allowed);
+        assert_eq!(
+            second.check(InputKind::Post, start),
+            Verdict::Limited(secs(1))
+        );
+        assert_eq!(elsewhere.check(InputKind::Post, start), Verdict::Allowed);
+
+        // The address is forgotten along with its last connection.
+        drop(first);
+        assert!(ip_limits.with(|ips| ips.contains_key(&IP)));
+        drop(second);
+        assert!(!ip_limits.with(|ips| ips.contains_key(&IP)));
+    }
+}
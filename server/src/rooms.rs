@@ -1,14 +1,134 @@
-use crate::proto::{MessageOutput, UserOutput};
-use std::collections::{HashMap, HashSet};
+use crate::config::{LimitConfig, RoomConfig, TimeoutConfig};
+use crate::credentials::{new_invite_code, verify_password};
+use crate::history::{BoxedHistoryStore, Conversation, HistoryQuery, SavedRoom};
+use crate::lobby::Lobby;
+use crate::messages::{
+    ClientActorMessage, CreateInvite, Delete, DeleteRoom, Disconnect, DroppedOutputs, Edit, Evict,
+    Grant, History, Join, Moderate, ModerationAction, Place, Placement, Resume, RoomDeleted,
+    RoomUpdated, SlowClient, Typing, UpdateRoom, WsMessage, SESSION_REPLACED_CLOSE_CODE,
+};
+use crate::permissions::Permission;
+use crate::proto::*;
+use crate::validation::username_key;
+use actix::prelude::{
+    Actor, ActorContext, ActorFutureExt, Addr, AsyncContext, Context, Handler, Recipient,
+    ResponseActFuture, SendError, SpawnHandle, WrapFuture,
+};
+use actix_web::web;
+use actix_web_actors::ws::{CloseCode, CloseReason};
+use bytestring::ByteString;
+use chrono::{DateTime, Utc};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
+type Socket = Recipient<WsMessage>;
+
+/// A client waiting for a seat in a full chat room.
+pub struct WaitingClient {
+    pub id: Uuid,
+    pub username: String,
+    pub addr: Recipient<WsMessage>,
+    pub ip: Option<IpAddr>,
+    pub dropped: DroppedOutputs,
+}
+
+/// The connection of a connected or waiting client. Outputs are queued in the
+/// client's mailbox, which only holds so many of them.
+struct Session {
+    addr: Socket,
+
+    /// The address the client is connected from, if known.
+    ip: Option<IpAddr>,
+
+    /// Outputs that were left out because the client's queue was full, shared
+    /// by every room the connection is in.
+    dropped: DroppedOutputs,
+
+    /// Set once the client has been reported to the lobby for not keeping up.
+    too_slow: Cell<bool>,
+}
+
+impl Session {
+    fn new(addr: Socket, ip: Option<IpAddr>, dropped: DroppedOutputs) -> Self {
+        Session {
+            addr,
+            ip,
+            dropped,
+            too_slow: Cell::new(false),
+        }
+    }
+}
+
+/// Whether a client's password or invite lets it into the room.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Admission {
+    Denied,
+    Admitted,
+    /// Admitted with an invite, which is used up once the client is seated or
+    /// queued.
+    Invited,
+    /// Admitted if the password matches the hash, which is checked off the
+    /// room's thread.
+    Password(String),
+}
+
+/// The messages that were posted with a nonce within the nonce window, so that
+/// a client retrying a post doesn't post the message twice.
+#[derive(Default)]
+struct RecentPosts {
+    /// Maps the poster and nonce to the message that was posted.
+    messages: HashMap<(Uuid, String), MessageId>,
+
+    /// The same posts, oldest first, with the time they were posted.
+    posted: VecDeque<(Instant, Uuid, String)>,
+}
+
+impl RecentPosts {
+    // Forgets the posts that are older than the window.
+    fn expire(&mut self, window: Duration, now: Instant) {
+        while let Some((posted_at, _, _)) = self.posted.front() {
+            if now.saturating_duration_since(*posted_at) < window {
+                break;
+            }
+            if let Some((_, client_id, nonce)) = self.posted.pop_front() {
+                self.messages.remove(&(client_id, nonce));
+            }
+        }
+    }
+
+    fn get(&self, client_id: Uuid, nonce: &str) -> Option<MessageId> {
+        self.messages.get(&(client_id, nonce.to_string())).copied()
+    }
+
+    fn insert(&mut self, client_id: Uuid, nonce: String, message_id: MessageId, now: Instant) {
+        self.messages.insert((client_id, nonce.clone()), message_id);
+        self.posted.push_back((now, client_id, nonce));
+    }
+}
+
+/// A user who may not join a room.
+pub struct Ban {
+    /// The address the user was connected from, which is banned as well.
+    pub ip: Option<IpAddr>,
+
+    /// When the ban ends, if it ever does.
+    pub until: Option<DateTime<Utc>>,
+}
+
 /// Used to represent a chat room which multiple clients can connect to and
-/// chat with each other. Currently each chat room keeps track of its own history
-/// of messages, but this should in the future be moved to an external database.
+/// chat with each other. Every room is an actor of its own, so that busy rooms
+/// don't hold up quiet ones. The history of messages is kept separately in a
+/// `HistoryStore` of the room's own.
 pub struct ChatRoom {
     /// Used to identify the chat room.
     pub id: Uuid,
     pub name: String,
+    pub topic: Option<String>,
 
     /// Maximum amount of clients allowed to connect to the chat room.
     pub max_clients: usize,
@@ -19,25 +139,245 @@ pub struct ChatRoom {
     /// Clients that are currently typing in the chat room.
     pub typing_clients: HashSet<Uuid>,
 
-    /// Chat history.
-    pub history: Vec<MessageOutput>,
+    /// Clients waiting for a seat to free up, in the order they asked to join.
+    pub waiting_clients: VecDeque<WaitingClient>,
+
+    /// The role of every user who doesn't have the default role.
+    pub roles: HashMap<Uuid, Role>,
+
+    /// The role of users who haven't been given one.
+    pub default_role: Role,
+
+    /// Whether the room is deleted after being empty for a while.
+    pub lifecycle: Lifecycle,
+
+    /// The user who created the room, for rooms created by clients rather
+    /// than configured. The settings of such rooms are saved with their
+    /// history if they are persistent.
+    pub creator: Option<Uuid>,
+
+    /// The hash of the password needed to join the room, if it has one.
+    pub password_hash: Option<String>,
+
+    /// Whether the room is left out of the rooms listed in the lobby.
+    pub private: bool,
+
+    /// Whether joining the room takes an invite.
+    pub invite_only: bool,
+
+    /// Unused invite codes, mapped to when they expire, if they ever do.
+    pub invites: HashMap<String, Option<DateTime<Utc>>>,
+
+    /// Users who may not join the room.
+    pub bans: HashMap<Uuid, Ban>,
+
+    /// Users who may not post, type or edit in the room, mapped to when the
+    /// mute ends, if it ever does.
+    pub muted: HashMap<Uuid, Option<DateTime<Utc>>>,
+
+    /// The session of every connected and waiting client.
+    sessions: HashMap<Uuid, Session>,
+
+    /// Clients that lost their connection, mapped to the timer that frees
+    /// their seat once the grace period is over.
+    detached: HashMap<Uuid, SpawnHandle>,
+
+    /// Messages recently posted with a nonce.
+    recent_posts: RecentPosts,
+
+    /// The timer that deletes an ephemeral room once it has been empty for
+    /// long enough.
+    idle_timer: Option<SpawnHandle>,
+
+    history: BoxedHistoryStore,
+    lobby: Addr<Lobby>,
+    limits: LimitConfig,
+    timeouts: TimeoutConfig,
 }
 
 impl ChatRoom {
-    pub fn new(id: Uuid, name: String, max_clients: usize) -> Self {
+    pub fn new(
+        id: Uuid,
+        name: String,
+        max_clients: usize,
+        history: BoxedHistoryStore,
+        lobby: Addr<Lobby>,
+        limits: LimitConfig,
+        timeouts: TimeoutConfig,
+    ) -> Self {
         ChatRoom {
             id,
             name,
+            topic: None,
             max_clients,
             clients: HashMap::new(),
             typing_clients: HashSet::new(),
-            history: Vec::new(),
+            waiting_clients: VecDeque::new(),
+            roles: HashMap::new(),
+            default_role: Role::default(),
+            lifecycle: Lifecycle::default(),
+            creator: None,
+            password_hash: None,
+            private: false,
+            invite_only: false,
+            invites: HashMap::new(),
+            bans: HashMap::new(),
+            muted: HashMap::new(),
+            sessions: HashMap::new(),
+            detached: HashMap::new(),
+            recent_posts: RecentPosts::default(),
+            idle_timer: None,
+            history,
+            lobby,
+            limits,
+            timeouts,
+        }
+    }
+
+    /// Returns the summary of the room that the lobby lists.
+    pub fn summary(&self) -> Room {
+        Room::new(
+            self.id,
+            self.name.clone(),
+            self.topic.clone(),
+            self.access(),
+            self.clients.len(),
+            self.max_clients,
+        )
+    }
+
+    /// Returns the settings the room would be started from again.
+    pub fn config(&self) -> RoomConfig {
+        let users_with = |role| {
+            self.roles
+                .iter()
+                .filter(|(_, user_role)| **user_role == role)
+                .map(|(id, _)| *id)
+                .collect()
+        };
+
+        RoomConfig {
+            id: self.id,
+            name: self.name.clone(),
+            topic: self.topic.clone(),
+            max_clients: Some(self.max_clients),
+            owners: users_with(Role::Owner),
+            moderators: users_with(Role::Moderator),
+            members: users_with(Role::Member),
+            guests: users_with(Role::Guest),
+            default_role: self.default_role,
+            lifecycle: self.lifecycle,
+            password_hash: self.password_hash.clone(),
+            private: self.private,
+            invite_only: self.invite_only,
+        }
+    }
+
+    // Saves the settings of a persistent room that a client created, as they
+    // aren't in the configuration.
+    fn save(&mut self) {
+        let creator = match self.creator {
+            Some(creator) if self.lifecycle == Lifecycle::Persistent => creator,
+            _ => return,
+        };
+
+        let room = SavedRoom {
+            creator,
+            config: self.config(),
+        };
+        if let Err(e) = self.history.save_room(&room) {
+            println!("Failed to save room {}: {}", self.id, e);
+        }
+    }
+
+    /// Returns who can find and join the room.
+    pub fn access(&self) -> Access {
+        Access {
+            private: self.private,
+            password: self.password_hash.is_some(),
+            invite_only: self.invite_only,
+        }
+    }
+
+    /// Checks whether the client may join the room with the password or
+    /// invite it gave. Moderators don't need either. Passwords are only
+    /// checked against the hash by `verify_password`, which is slow.
+    pub fn check_credentials(
+        &self,
+        client_id: &Uuid,
+        password: Option<&str>,
+        invite: Option<&str>,
+    ) -> Admission {
+        if self.get_role(client_id).allows(Permission::Moderate) {
+            return Admission::Admitted;
+        }
+
+        if invite.is_some_and(|code| self.invites.contains_key(code)) {
+            return Admission::Invited;
+        }
+
+        if self.invite_only {
+            return Admission::Denied;
+        }
+
+        match (&self.password_hash, password) {
+            (None, _) => Admission::Admitted,
+            (Some(hash), Some(_)) => Admission::Password(hash.clone()),
+            (Some(_), None) => Admission::Denied,
+        }
+    }
+
+    // Invites can only be used once.
+    fn use_invite(&mut self, invite: Option<&str>) {
+        if let Some(code) = invite {
+            self.invites.remove(code);
+        }
+    }
+
+    /// Returns true if no more clients can join the chat room.
+    pub fn is_full(&self) -> bool {
+        self.clients.len() >= self.max_clients
+    }
+
+    /// Returns true if a connected or waiting client already goes by a name that
+    /// can be confused with `username`.
+    pub fn is_username_taken(&self, username: &str) -> bool {
+        let key = username_key(username);
+
+        self.clients
+            .values()
+            .chain(self.waiting_clients.iter().map(|client| &client.username))
+            .any(|name| username_key(name) == key)
+    }
+
+    /// Adds a client to the back of the waiting queue and returns its
+    /// (1-based) position in the queue.
+    pub fn add_waiting_client(&mut self, client: WaitingClient) -> usize {
+        self.waiting_clients.push_back(client);
+        self.waiting_clients.len()
+    }
+
+    /// Removes a client from the waiting queue. Returns true if the client was
+    /// waiting.
+    pub fn remove_waiting_client(&mut self, client_id: &Uuid) -> bool {
+        let len = self.waiting_clients.len();
+        self.waiting_clients
+            .retain(|client| client.id != *client_id);
+        self.waiting_clients.len() != len
+    }
+
+    /// Takes the next waiting client from the queue if there is a free seat.
+    pub fn next_waiting_client(&mut self) -> Option<WaitingClient> {
+        if self.is_full() {
+            None
+        } else {
+            self.waiting_clients.pop_front()
         }
     }
 
     /// Adds a new client to the set of connected clients.
     pub fn add_client(&mut self, client_id: &Uuid, username: String) {
-        self.clients.insert(client_id.clone(), username);
+        self.clients.insert(*client_id, username);
     }
 
     /// Removes a client from the set of connected clients.
@@ -50,9 +390,17 @@ impl ChatRoom {
         self.clients.get(client_id)
     }
 
+    /// Returns a list of all connected clients (id, username).
+    pub fn get_clients(&self) -> Vec<UserOutput> {
+        self.clients
+            .iter()
+            .map(|(client_id, username)| UserOutput::new(*client_id, username))
+            .collect()
+    }
+
     /// Adds a client to the list of typing clients.
     pub fn add_typing_client(&mut self, client_id: &Uuid) {
-        self.typing_clients.insert(client_id.clone());
+        self.typing_clients.insert(*client_id);
     }
 
     /// Removes a client from the list of typing clients.
@@ -60,14 +408,1148 @@ impl ChatRoom {
         self.typing_clients.remove(client_id)
     }
 
+    /// Returns the role a user has in the room.
+    pub fn get_role(&self, client_id: &Uuid) -> Role {
+        self.roles
+            .get(client_id)
+            .copied()
+            .unwrap_or(self.default_role)
+    }
+
+    /// Gives a user a role in the room.
+    pub fn set_role(&mut self, client_id: &Uuid, role: Role) {
+        if role == self.default_role {
+            self.roles.remove(client_id);
+        } else {
+            self.roles.insert(*client_id, role);
+        }
+    }
+
+    /// Returns the roles of the room as they are sent to clients.
+    pub fn get_roles(&self) -> RolesOutput {
+        RolesOutput::new(self.default_role, self.roles.clone())
+    }
+
+    /// Checks that a client has a seat in the room and may do what it's
+    /// trying to do there, returning its username. Muted clients can't post or
+    /// type.
+    pub fn check_permission(
+        &self,
+        client_id: &Uuid,
+        permission: Permission,
+    ) -> Result<String, OutputError> {
+        // Clients still waiting for a seat can't take part in the room.
+        let username = self.get_username(client_id).ok_or(OutputError::NotJoined)?;
+
+        if !self.get_role(client_id).allows(permission) {
+            return Err(OutputError::Forbidden);
+        }
+
+        if matches!(permission, Permission::Post | Permission::Type)
+            && self.is_muted(client_id, Utc::now())
+        {
+            return Err(OutputError::Muted);
+        }
+
+        Ok(username.clone())
+    }
+
+    /// Returns true if the user, or the address it connects from, is banned
+    /// from the room.
+    pub fn is_banned(&self, client_id: &Uuid, ip: Option<IpAddr>, now: DateTime<Utc>) -> bool {
+        self.bans.iter().any(|(banned_id, ban)| {
+            (banned_id == client_id || (ip.is_some() && ban.ip == ip))
+                && ban.until.is_none_or(|until| until > now)
+        })
+    }
+
+    /// Returns true if the user is muted in the room.
+    pub fn is_muted(&self, client_id: &Uuid, now: DateTime<Utc>) -> bool {
+        self.muted
+            .get(client_id)
+            .is_some_and(|until| until.is_none_or(|until| until > now))
+    }
+
+    /// Forgets the bans and mutes that have ended.
+    pub fn expire_moderation(&mut self, now: DateTime<Utc>) {
+        self.bans
+            .retain(|_, ban| ban.until.is_none_or(|until| until > now));
+        self.muted
+            .retain(|_, until| until.is_none_or(|until| until > now));
+        self.invites
+            .retain(|_, until| until.is_none_or(|until| until > now));
+    }
+
     /// Returns a list of all clients (id, username) who are currently typing
     /// in the chat room.
     pub fn get_typing_clients(&self) -> Vec<UserOutput> {
         self.typing_clients
             .iter()
-            .map(|client_id| {
-                UserOutput::new(client_id.clone(), self.get_username(client_id).unwrap())
+            .filter_map(|client_id| {
+                self.get_username(client_id)
+                    .map(|username| UserOutput::new(*client_id, username))
             })
             .collect()
     }
 }
+
+impl ChatRoom {
+    // Sends a serialized output to a single client. Only the reference to the
+    // text is copied. If the client's queue is full, droppable outputs are
+    // left out and otherwise the client is reported to the lobby as too slow.
+    fn send_message(&self, text: &ByteString, id_to: &Uuid, droppable: bool) {
+        let session = match self.sessions.get(id_to) {
+            Some(session) => session,
+            None => return println!("Attempting to send message but couldn't find client id."),
+        };
+
+        // The client is about to be disconnected.
+        if session.too_slow.get() {
+            return;
+        }
+
+        match session.addr.try_send(WsMessage::Text(text.clone())) {
+            Ok(()) => (),
+            Err(SendError::Full(_)) if droppable => {
+                session.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(SendError::Full(_)) => {
+                session.too_slow.set(true);
+                self.lobby.do_send(SlowClient { client_id: *id_to });
+            }
+            // The connection is gone, the room hears about it soon enough.
+            Err(SendError::Closed(_)) => (),
+        }
+    }
+
+    // Sends an output to a single client.
+    fn send_output(&self, output: &Output, id_to: &Uuid) {
+        self.send_message(&output.to_room_text(self.id), id_to, output.is_droppable());
+    }
+
+    // Sends an error to a single client.
+    fn send_error(&self, error: OutputError, id_to: &Uuid) {
+        self.send_output(&Output::Error(error), id_to);
+    }
+
+    // Sends an output to every client connected to the room. The output is
+    // serialized once and shared by all of them. Clients that lost their
+    // connection and haven't resumed yet are skipped.
+    fn send_to_everyone(&self, output: &Output) {
+        let text = output.to_room_text(self.id);
+        let droppable = output.is_droppable();
+        self.clients
+            .keys()
+            .filter(|client_id| !self.detached.contains_key(client_id))
+            .for_each(|client_id| self.send_message(&text, client_id, droppable));
+    }
+
+    // Sends an output to every client connected to the room except one client
+    // specified by `self_id`.
+    fn send_to_everyone_except_self(&self, self_id: &Uuid, output: &Output) {
+        let text = output.to_room_text(self.id);
+        let droppable = output.is_droppable();
+        self.clients
+            .keys()
+            .filter(|client_id| *client_id.to_owned() != *self_id)
+            .filter(|client_id| !self.detached.contains_key(client_id))
+            .for_each(|client_id| self.send_message(&text, client_id, droppable));
+    }
+
+    // Forgets the connection of a client, returning its socket.
+    fn end_session(&mut self, client_id: &Uuid) -> Option<Socket> {
+        self.sessions.remove(client_id).map(|session| session.addr)
+    }
+
+    // Tells the lobby where a client is in the room.
+    fn place(&self, client_id: Uuid, place: Option<Place>) {
+        self.lobby.do_send(Placement {
+            client_id,
+            room_id: self.id,
+            place,
+        });
+    }
+
+    // Tells the lobby how many clients are in the room.
+    fn report(&self) {
+        self.lobby.do_send(RoomUpdated {
+            room: self.summary(),
+        });
+    }
+
+    // Gives a client that has a free seat its seat and tells everyone about it.
+    fn seat_client(
+        &mut self,
+        self_id: Uuid,
+        username: String,
+        addr: Socket,
+        ip: Option<IpAddr>,
+        dropped: DroppedOutputs,
+    ) {
+        // Echo to everyone in the room that a new client just joined.
+        self.send_to_everyone_except_self(
+            &self_id,
+            &Output::UserJoined(UserJoinedOutput::new(UserOutput::new(self_id, &username))),
+        );
+
+        // Add the client to the chatroom.
+        self.add_client(&self_id, username.clone());
+
+        // Store the address of the client in the sessions hashmap.
+        self.sessions
+            .insert(self_id, Session::new(addr.clone(), ip, dropped));
+
+        // Get the most recent chat history for the room, older messages are
+        // fetched by the client on demand.
+        let history = self.history.range(
+            &Conversation::Room(self.id),
+            &HistoryQuery::latest(self.limits.joined_history_limit),
+        );
+        let (room_chat_history, cursor) = match history {
+            Ok(page) => {
+                let cursor = page.cursor(false);
+                (page.messages, cursor)
+            }
+            Err(e) => {
+                println!("Failed to read the history of room {}: {}", self.id, e);
+                (Vec::new(), None)
+            }
+        };
+
+        // Hand out a token that lets the client get its seat back if it loses
+        // its connection.
+        let resume_token = Uuid::new_v4();
+        self.place(
+            self_id,
+            Some(Place::Seated {
+                username: username.clone(),
+                addr,
+                resume_token,
+            }),
+        );
+        self.report();
+
+        // Send the client information that the join was successful, along with
+        // information about other connected clients and the history of the
+        // chatroom.
+        self.send_output(
+            &Output::Joined(JoinedOutput::new(
+                UserOutput::new(self_id, &username),
+                self.get_clients(),
+                room_chat_history,
+                cursor,
+                self.get_typing_clients(),
+                self.get_roles(),
+                resume_token,
+            )),
+            &self_id,
+        );
+    }
+
+    // Looks up a message that a client wants to change. Only the author of a
+    // message may change it (or a client with the `others` permission, if
+    // given), and deleted messages can't be changed at all.
+    fn own_message(
+        &self,
+        client_id: &Uuid,
+        message_id: MessageId,
+        others: Option<Permission>,
+    ) -> Result<MessageOutput, OutputError> {
+        self.check_permission(client_id, Permission::Read)?;
+
+        let message = match self.history.get(&Conversation::Room(self.id), message_id) {
+            Ok(Some(message)) if !message.deleted => message,
+            Ok(_) => return Err(OutputError::MessageNotFound),
+            Err(e) => {
+                println!("Failed to read message in room {}: {}", self.id, e);
+                return Err(OutputError::Internal);
+            }
+        };
+
+        let permission = if message.user.id == *client_id {
+            Permission::ChangeOwn
+        } else {
+            others.ok_or(OutputError::Forbidden)?
+        };
+        self.check_permission(client_id, permission)?;
+
+        Ok(message)
+    }
+
+    // Removes a client from the room for good and tells everyone about it.
+    fn unseat_client(&mut self, client_id: Uuid) {
+        // Remove the client from the room, unless it is already gone.
+        let username = match self.remove_client(&client_id) {
+            Some(username) => username,
+            None => return,
+        };
+        self.end_session(&client_id);
+
+        // The client's resume token is no use anymore.
+        self.place(client_id, None);
+        self.report();
+
+        // If the client was typing, send out a message that they've stopped
+        // typing to all clients.
+        if self.remove_typing_client(&client_id) {
+            self.send_to_everyone(&Output::Typing(TypingOutput::new(
+                TypingStatus::Stopped,
+                UserOutput::new(client_id, &username),
+            )));
+        }
+
+        // Send message to all other clients in the room that the client has
+        // disconnected.
+        self.send_to_everyone(&Output::UserLeft(UserLeftOutput::new(client_id, &username)));
+
+        // Give the free seat to the next client in line.
+        self.admit_waiting_clients();
+    }
+
+    // Takes a client out of the waiting queue. Returns true if the client was
+    // waiting.
+    fn unqueue_client(&mut self, client_id: Uuid) -> bool {
+        if !self.remove_waiting_client(&client_id) {
+            return false;
+        }
+
+        self.end_session(&client_id);
+        self.place(client_id, None);
+        self.admit_waiting_clients();
+        true
+    }
+
+    // Keeps the seat of a client that lost its connection for a grace period,
+    // during which the client can resume its session.
+    fn detach_client(&mut self, client_id: Uuid, ctx: &mut Context<Self>) {
+        // Only clients with a seat have anything to hold on to.
+        let username = match self.get_username(&client_id) {
+            Some(username) => username.clone(),
+            None => return,
+        };
+
+        // A client without a connection isn't typing anymore.
+        if self.remove_typing_client(&client_id) {
+            self.send_to_everyone(&Output::Typing(TypingOutput::new(
+                TypingStatus::Stopped,
+                UserOutput::new(client_id, &username),
+            )));
+        }
+
+        self.place(client_id, Some(Place::Detached));
+
+        let handle = ctx.run_later(self.timeouts.resume_grace_period, move |act, ctx| {
+            act.detached.remove(&client_id);
+            act.unseat_client(client_id);
+            act.watch_idle(ctx);
+        });
+        self.detached.insert(client_id, handle);
+    }
+
+    // Takes a kicked or banned client out of the room, telling it why with
+    // `output`.
+    fn remove_client_by_moderator(
+        &mut self,
+        client_id: Uuid,
+        output: &Output,
+        ctx: &mut Context<Self>,
+    ) {
+        if let Some(handle) = self.detached.remove(&client_id) {
+            ctx.cancel_future(handle);
+        }
+
+        if let Some(socket) = self.end_session(&client_id) {
+            socket.do_send(WsMessage::Removed(self.id, output.to_room_text(self.id)));
+        }
+
+        if !self.unqueue_client(client_id) {
+            self.unseat_client(client_id);
+        }
+    }
+
+    // Starts the timer that deletes an ephemeral room once it is empty, or
+    // stops it once someone is in the room again. Clients that lost their
+    // connection still count, they may come back.
+    fn watch_idle(&mut self, ctx: &mut Context<Self>) {
+        let is_empty = self.clients.is_empty() && self.waiting_clients.is_empty();
+
+        match self.idle_timer {
+            None if is_empty && self.lifecycle == Lifecycle::Ephemeral => {
+                let handle = ctx.run_later(self.timeouts.idle_room_timeout, |act, ctx| {
+                    println!("Removing room {} that has been empty for too long.", act.id);
+                    act.close(ctx);
+                });
+                self.idle_timer = Some(handle);
+            }
+            Some(handle) if !is_empty => {
+                ctx.cancel_future(handle);
+                self.idle_timer = None;
+            }
+            _ => (),
+        }
+    }
+
+    // Deletes the room and its history, once everyone has been sent back to
+    // the lobby.
+    fn close(&mut self, ctx: &mut Context<Self>) {
+        // Let the lobby forget the room before anyone asks it for the rooms
+        // that are left.
+        self.lobby.do_send(RoomDeleted { room_id: self.id });
+
+        if let Err(e) = self.history.delete(&Conversation::Room(self.id)) {
+            println!("Failed to delete the history of room {}: {}", self.id, e);
+        }
+        if self.creator.is_some() {
+            if let Err(e) = self.history.delete_room(self.id) {
+                println!("Failed to delete room {}: {}", self.id, e);
+            }
+        }
+
+        ctx.stop();
+    }
+
+    // Lets waiting clients into the room for as long as there are free seats,
+    // and tells the ones still waiting about their new position in the queue.
+    fn admit_waiting_clients(&mut self) {
+        while let Some(client) = self.next_waiting_client() {
+            self.seat_client(
+                client.id,
+                client.username,
+                client.addr,
+                client.ip,
+                client.dropped,
+            );
+        }
+
+        self.waiting_clients
+            .iter()
+            .enumerate()
+            .for_each(|(index, client)| {
+                self.send_output(
+                    &Output::Queued(QueuedOutput::new(self.id, index + 1)),
+                    &client.id,
+                )
+            });
+    }
+
+    // Lets a client whose credentials were checked into the room, or into its
+    // waiting queue. Returns whether it was let in.
+    fn join(&mut self, msg: Join, admission: Admission, ctx: &mut Context<Self>) -> bool {
+        let now = Utc::now();
+        self.expire_moderation(now);
+
+        if self.is_banned(&msg.self_id, msg.ip, now) {
+            msg.addr.do_send(WsMessage::Text(
+                Output::Error(OutputError::Banned).to_room_text(self.id),
+            ));
+            return false;
+        }
+
+        if admission == Admission::Denied {
+            msg.addr.do_send(WsMessage::Text(
+                Output::Error(OutputError::BadCredentials).to_room_text(self.id),
+            ));
+            return false;
+        }
+
+        // Usernames have to be unique within a room.
+        if self.is_username_taken(&msg.username) {
+            msg.addr.do_send(WsMessage::Text(
+                Output::Error(OutputError::NameTaken).to_room_text(self.id),
+            ));
+            return false;
+        }
+
+        if self.is_full() {
+            if !msg.wait {
+                msg.addr.do_send(WsMessage::Text(
+                    Output::Error(OutputError::RoomFull).to_room_text(self.id),
+                ));
+                return false;
+            }
+
+            if admission == Admission::Invited {
+                self.use_invite(msg.invite.as_deref());
+            }
+
+            // Put the client in line for the next free seat.
+            let position = self.add_waiting_client(WaitingClient {
+                id: msg.self_id,
+                username: msg.username,
+                addr: msg.addr.clone(),
+                ip: msg.ip,
+                dropped: Arc::clone(&msg.dropped),
+            });
+            self.sessions.insert(
+                msg.self_id,
+                Session::new(msg.addr.clone(), msg.ip, msg.dropped),
+            );
+            self.place(msg.self_id, Some(Place::Waiting { addr: msg.addr }));
+            self.send_output(
+                &Output::Queued(QueuedOutput::new(self.id, position)),
+                &msg.self_id,
+            );
+
+            return true;
+        }
+
+        if admission == Admission::Invited {
+            self.use_invite(msg.invite.as_deref());
+        }
+
+        self.seat_client(msg.self_id, msg.username, msg.addr, msg.ip, msg.dropped);
+        self.watch_idle(ctx);
+
+        true
+    }
+}
+
+impl Actor for ChatRoom {
+    type Context = Context<Self>;
+
+    // A room starts out empty.
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.watch_idle(ctx);
+    }
+}
+
+impl Handler<Join> for ChatRoom {
+    type Result = ResponseActFuture<Self, bool>;
+
+    fn handle(&mut self, msg: Join, _: &mut Context<Self>) -> Self::Result {
+        let password = msg.password.clone();
+        let admission =
+            self.check_credentials(&msg.self_id, password.as_deref(), msg.invite.as_deref());
+
+        // Hashing the password takes long enough to hold up everyone in the
+        // room, so it is checked on the blocking thread pool. The room goes
+        // on with other messages in the meantime.
+        let admission = async move {
+            match (admission, password) {
+                (Admission::Password(hash), Some(password)) => {
+                    match web::block(move || verify_password(&password, &hash)).await {
+                        Ok(true) => Admission::Admitted,
+                        Ok(false) => Admission::Denied,
+                        Err(e) => {
+                            println!("Failed to check a room password: {}", e);
+                            Admission::Denied
+                        }
+                    }
+                }
+                (admission, _) => admission,
+            }
+        };
+
+        Box::pin(
+            admission
+                .into_actor(self)
+                .map(move |admission, act, ctx| act.join(msg, admission, ctx)),
+        )
+    }
+}
+
+impl Handler<Disconnect> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Context<Self>) {
+        // Ignore connections that have been replaced by a resumed session.
+        if self.sessions.get(&msg.self_id).map(|session| &session.addr) != Some(&msg.addr) {
+            return;
+        }
+
+        // A client that was still waiting for a seat only has to leave the
+        // queue.
+        if self.unqueue_client(msg.self_id) {
+            return;
+        }
+
+        self.end_session(&msg.self_id);
+
+        if msg.resumable {
+            self.detach_client(msg.self_id, ctx);
+        } else {
+            self.unseat_client(msg.self_id);
+            self.watch_idle(ctx);
+        }
+    }
+}
+
+impl Handler<Evict> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: Evict, ctx: &mut Context<Self>) {
+        let client_id = msg.client_id;
+
+        if let Some(old_socket) = self.end_session(&client_id) {
+            old_socket.do_send(WsMessage::Close(Some(msg.reason)));
+        }
+
+        if let Some(handle) = self.detached.remove(&client_id) {
+            ctx.cancel_future(handle);
+        }
+
+        if !self.unqueue_client(client_id) {
+            self.unseat_client(client_id);
+        }
+
+        self.watch_idle(ctx);
+    }
+}
+
+impl Handler<Resume> for ChatRoom {
+    type Result = bool;
+
+    fn handle(&mut self, msg: Resume, ctx: &mut Context<Self>) -> bool {
+        let client_id = msg.self_id;
+
+        // The seat may have been given up since the token was handed out.
+        let username = match self.get_username(&client_id) {
+            Some(username) => username.clone(),
+            None => {
+                msg.addr.do_send(WsMessage::Text(
+                    Output::Error(OutputError::InvalidResumeToken).to_room_text(self.id),
+                ));
+                return false;
+            }
+        };
+
+        if let Some(handle) = self.detached.remove(&client_id) {
+            // The seat is no longer up for grabs.
+            ctx.cancel_future(handle);
+        } else if let Some(old_socket) = self.end_session(&client_id) {
+            // The old connection is still around, but the client has moved on
+            // to the new one.
+            old_socket.do_send(WsMessage::Close(Some(CloseReason {
+                code: CloseCode::Other(SESSION_REPLACED_CLOSE_CODE),
+                description: Some("Session resumed on another connection".to_string()),
+            })));
+        }
+
+        self.sessions.insert(
+            client_id,
+            Session::new(msg.addr.clone(), msg.ip, msg.dropped),
+        );
+
+        // Replay everything the client missed, or the most recent messages if
+        // it hasn't seen any.
+        let query = match msg.last_seen {
+            Some(last_seen) => HistoryQuery {
+                before: None,
+                after: Some(last_seen),
+                limit: self.limits.max_history_page_size,
+            },
+            None => HistoryQuery::latest(self.limits.joined_history_limit),
+        };
+        let history = self.history.range(&Conversation::Room(self.id), &query);
+        let (messages, cursor) = match history {
+            Ok(page) => {
+                let cursor = page.cursor(query.after.is_some());
+                (page.messages, cursor)
+            }
+            Err(e) => {
+                println!("Failed to read the history of room {}: {}", self.id, e);
+                (Vec::new(), None)
+            }
+        };
+
+        let resume_token = Uuid::new_v4();
+        self.place(
+            client_id,
+            Some(Place::Seated {
+                username: username.clone(),
+                addr: msg.addr,
+                resume_token,
+            }),
+        );
+
+        self.send_output(
+            &Output::Resumed(ResumedOutput::new(
+                UserOutput::new(client_id, &username),
+                self.get_clients(),
+                messages,
+                cursor,
+                self.get_typing_clients(),
+                self.get_roles(),
+                resume_token,
+            )),
+            &client_id,
+        );
+
+        true
+    }
+}
+
+impl Handler<Typing> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: Typing, _: &mut Context<Self>) {
+        let username = match self.check_permission(&msg.id, Permission::Type) {
+            Ok(username) => username,
+            Err(error) => return self.send_error(error, &msg.id),
+        };
+
+        // Add or remove the client from the typing clients in the room.
+        match msg.status {
+            TypingStatus::Started => self.add_typing_client(&msg.id),
+            TypingStatus::Stopped => {
+                self.remove_typing_client(&msg.id);
+            }
+        }
+
+        // Construct the message to send out to all other users.
+        let message = Output::Typing(TypingOutput::new(
+            msg.status,
+            UserOutput::new(msg.id, &username),
+        ));
+
+        // Echo to all other users that the client is typing.
+        self.send_to_everyone_except_self(&msg.id, &message);
+    }
+}
+
+impl Handler<ClientActorMessage> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: ClientActorMessage, _: &mut Context<Self>) {
+        // Timestamp for when the message was received.
+        let timestamp: DateTime<Utc> = Utc::now();
+
+        // Get the username from the room.
+        let username = match self.check_permission(&msg.id, Permission::Post) {
+            Ok(username) => username,
+            Err(error) => return self.send_error(error, &msg.id),
+        };
+
+        // A post that is retried with the same nonce gets the message that was
+        // posted the first time.
+        if let Some(nonce) = &msg.nonce {
+            let now = Instant::now();
+            self.recent_posts.expire(self.timeouts.nonce_window, now);
+
+            if let Some(message_id) = self.recent_posts.get(msg.id, nonce) {
+                let posted = self.history.get(&Conversation::Room(self.id), message_id);
+                match posted {
+                    Ok(Some(message_output)) => {
+                        return self.send_output(
+                            &Output::Posted(PostedOutput::new(message_output, msg.nonce)),
+                            &msg.id,
+                        );
+                    }
+                    Ok(None) => (),
+                    Err(e) => {
+                        println!("Failed to read message in room {}: {}", self.id, e);
+                        return self.send_error(OutputError::Internal, &msg.id);
+                    }
+                }
+            }
+        }
+
+        // Push the message to the history, which gives it its id, to construct
+        // the message to be sent to all clients in the chat room.
+        let appended = self.history.append(
+            &Conversation::Room(self.id),
+            UserOutput::new(msg.id, &username),
+            &msg.msg,
+            timestamp,
+        );
+        let message_output = match appended {
+            Ok(message_output) => message_output,
+            Err(e) => {
+                println!("Failed to store message in room {}: {}", self.id, e);
+                return self.send_error(OutputError::Internal, &msg.id);
+            }
+        };
+
+        if let Some(nonce) = &msg.nonce {
+            self.recent_posts
+                .insert(msg.id, nonce.clone(), message_output.id, Instant::now());
+        }
+
+        // Send the message to all other clients in the chatroom.
+        self.send_to_everyone_except_self(
+            &msg.id,
+            &Output::UserPosted(UserPostedOutput::new(message_output.clone())),
+        );
+
+        // Send information about the message to the client that sent it.
+        self.send_output(
+            &Output::Posted(PostedOutput::new(message_output, msg.nonce)),
+            &msg.id,
+        );
+    }
+}
+
+impl Handler<History> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: History, _: &mut Context<Self>) {
+        // Clients still waiting for a seat can't read the room's history.
+        if let Err(error) = self.check_permission(&msg.id, Permission::Read) {
+            return self.send_error(error, &msg.id);
+        }
+
+        let conversation = Conversation::Room(self.id);
+        let query = HistoryQuery {
+            before: msg.before,
+            after: msg.after,
+            limit: msg
+                .limit
+                .unwrap_or(self.limits.max_history_page_size)
+                .min(self.limits.max_history_page_size),
+        };
+
+        let page = self.history.range(&conversation, &query);
+        match page {
+            Ok(page) => {
+                let cursor = page.cursor(query.after.is_some());
+                self.send_output(
+                    &Output::History(HistoryOutput::new(None, page.messages, cursor)),
+                    &msg.id,
+                );
+            }
+            Err(e) => {
+                println!("Failed to read the history of {}: {}", conversation, e);
+                self.send_error(OutputError::Internal, &msg.id);
+            }
+        }
+    }
+}
+
+impl Handler<Edit> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: Edit, _: &mut Context<Self>) {
+        let mut message = match self.own_message(&msg.id, msg.message_id, None) {
+            Ok(message) => message,
+            Err(error) => return self.send_error(error, &msg.id),
+        };
+
+        if self.is_muted(&msg.id, Utc::now()) {
+            return self.send_error(OutputError::Muted, &msg.id);
+        }
+
+        message.edit(&msg.body, Utc::now());
+
+        if let Err(e) = self.history.update(&Conversation::Room(self.id), &message) {
+            println!("Failed to edit message in room {}: {}", self.id, e);
+            return self.send_error(OutputError::Internal, &msg.id);
+        }
+
+        // Send the new version of the message to everyone in the room,
+        // including the author.
+        self.send_to_everyone(&Output::MessageEdited(MessageEditedOutput::new(message)));
+    }
+}
+
+impl Handler<Delete> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: Delete, _: &mut Context<Self>) {
+        // Moderators can delete anyone's messages.
+        let mut message =
+            match self.own_message(&msg.id, msg.message_id, Some(Permission::DeleteAny)) {
+                Ok(message) => message,
+                Err(error) => return self.send_error(error, &msg.id),
+            };
+
+        // The message stays in the history as a tombstone so that clients
+        // paging through the history see that it was deleted.
+        message.delete();
+
+        if let Err(e) = self.history.update(&Conversation::Room(self.id), &message) {
+            println!("Failed to delete message in room {}: {}", self.id, e);
+            return self.send_error(OutputError::Internal, &msg.id);
+        }
+
+        self.send_to_everyone(&Output::MessageDeleted(MessageDeletedOutput::new(message)));
+    }
+}
+
+impl Handler<Moderate> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: Moderate, ctx: &mut Context<Self>) {
+        let moderator = match self.check_permission(&msg.id, Permission::Moderate) {
+            Ok(username) => UserOutput::new(msg.id, &username),
+            Err(error) => return self.send_error(error, &msg.id),
+        };
+
+        // Moderators can't moderate themselves or anyone with a role as high
+        // as theirs.
+        if msg.user == msg.id || !self.get_role(&msg.id).outranks(self.get_role(&msg.user)) {
+            return self.send_error(OutputError::Forbidden, &msg.id);
+        }
+
+        let now = Utc::now();
+        self.expire_moderation(now);
+
+        // Bans and mutes that would end too far in the future never end.
+        let until = |duration: Option<Duration>| {
+            duration
+                .and_then(|duration| chrono::Duration::from_std(duration).ok())
+                .and_then(|duration| now.checked_add_signed(duration))
+        };
+        let is_present =
+            self.sessions.contains_key(&msg.user) || self.clients.contains_key(&msg.user);
+
+        let user = msg.user;
+        let output = |action, reason, until| {
+            Output::Moderated(ModeratedOutput::new(
+                action,
+                user,
+                moderator.clone(),
+                reason,
+                until,
+            ))
+        };
+
+        match msg.action {
+            ModerationAction::Kick { reason } => {
+                if !is_present {
+                    return self.send_error(OutputError::UserNotConnected, &msg.id);
+                }
+
+                let output = output(Moderation::Kicked, reason, None);
+                self.remove_client_by_moderator(msg.user, &output, ctx);
+                self.send_to_everyone(&output);
+            }
+            ModerationAction::Ban {
+                reason,
+                duration,
+                ip,
+            } => {
+                // An address can only be banned while the user is connected
+                // from it.
+                let ip = if ip {
+                    match self.sessions.get(&msg.user).and_then(|session| session.ip) {
+                        Some(ip) => Some(ip),
+                        None => return self.send_error(OutputError::UserNotConnected, &msg.id),
+                    }
+                } else {
+                    None
+                };
+                let until = until(duration);
+                self.bans.insert(msg.user, Ban { ip, until });
+
+                let output = output(Moderation::Banned, reason, until);
+                if is_present {
+                    self.remove_client_by_moderator(msg.user, &output, ctx);
+                }
+                self.send_to_everyone(&output);
+            }
+            ModerationAction::Unban => {
+                if self.bans.remove(&msg.user).is_none() {
+                    return self.send_error(OutputError::InvalidInput, &msg.id);
+                }
+
+                self.send_to_everyone(&output(Moderation::Unbanned, None, None));
+            }
+            ModerationAction::Mute { reason, duration } => {
+                let until = until(duration);
+                self.muted.insert(msg.user, until);
+
+                // A muted client isn't typing anymore.
+                if let Some(username) = self.get_username(&msg.user).cloned() {
+                    if self.remove_typing_client(&msg.user) {
+                        self.send_to_everyone(&Output::Typing(TypingOutput::new(
+                            TypingStatus::Stopped,
+                            UserOutput::new(msg.user, &username),
+                        )));
+                    }
+                }
+
+                let output = output(Moderation::Muted, reason, until);
+                self.send_to_everyone(&output);
+
+                // A client still waiting for a seat hears about it as well.
+                if !self.clients.contains_key(&msg.user) && is_present {
+                    self.send_output(&output, &msg.user);
+                }
+            }
+            ModerationAction::Unmute => {
+                if self.muted.remove(&msg.user).is_none() {
+                    return self.send_error(OutputError::InvalidInput, &msg.id);
+                }
+
+                let output = output(Moderation::Unmuted, None, None);
+                self.send_to_everyone(&output);
+                if !self.clients.contains_key(&msg.user) && is_present {
+                    self.send_output(&output, &msg.user);
+                }
+            }
+        }
+    }
+}
+
+impl Handler<Grant> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: Grant, _: &mut Context<Self>) {
+        let granter = match self.check_permission(&msg.id, Permission::GrantRoles) {
+            Ok(username) => UserOutput::new(msg.id, &username),
+            Err(error) => return self.send_error(error, &msg.id),
+        };
+
+        // Revoking a role puts the user back to the default role.
+        let role = msg.role.unwrap_or(self.default_role);
+        let granter_role = self.get_role(&msg.id);
+        let current = self.get_role(&msg.user);
+
+        // Nobody can change their own role, or give or take a role that is as
+        // high as theirs.
+        if msg.user == msg.id || !granter_role.outranks(current) || !granter_role.can_grant(role) {
+            return self.send_error(OutputError::Forbidden, &msg.id);
+        }
+
+        if role == current {
+            return self.send_error(OutputError::InvalidInput, &msg.id);
+        }
+
+        self.set_role(&msg.user, role);
+        self.save();
+
+        // A client that can no longer type isn't typing anymore.
+        if !role.allows(Permission::Type) {
+            if let Some(username) = self.get_username(&msg.user).cloned() {
+                if self.remove_typing_client(&msg.user) {
+                    self.send_to_everyone(&Output::Typing(TypingOutput::new(
+                        TypingStatus::Stopped,
+                        UserOutput::new(msg.user, &username),
+                    )));
+                }
+            }
+        }
+
+        let output = Output::RoleChanged(RoleChangedOutput::new(msg.user, role, granter));
+        self.send_to_everyone(&output);
+
+        // A client still waiting for a seat hears about it as well.
+        if !self.clients.contains_key(&msg.user) && self.sessions.contains_key(&msg.user) {
+            self.send_output(&output, &msg.user);
+        }
+    }
+}
+
+impl Handler<UpdateRoom> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: UpdateRoom, _: &mut Context<Self>) {
+        // Owners don't have to be in the room to change it.
+        if !self.get_role(&msg.id).allows(Permission::ManageRoom) {
+            msg.addr.do_send(WsMessage::Text(
+                Output::Error(OutputError::Forbidden).to_room_text(self.id),
+            ));
+            return;
+        }
+
+        if let Some(name) = msg.name {
+            self.name = name;
+        }
+        if let Some(topic) = msg.topic {
+            self.topic = Some(topic).filter(|topic| !topic.is_empty());
+        }
+        if let Some(max_clients) = msg.max_clients {
+            // Clients that are already seated keep their seats if the room
+            // shrinks.
+            self.max_clients = max_clients;
+        }
+        if let Some(password_hash) = msg.password_hash {
+            self.password_hash = password_hash;
+        }
+        if let Some(private) = msg.private {
+            self.private = private;
+        }
+        if let Some(invite_only) = msg.invite_only {
+            self.invite_only = invite_only;
+        }
+
+        self.save();
+        self.report();
+
+        let output = Output::RoomUpdated(RoomUpdatedOutput::new(self.summary()));
+        self.send_to_everyone(&output);
+        if !self.clients.contains_key(&msg.id) {
+            msg.addr
+                .do_send(WsMessage::Text(output.to_room_text(self.id)));
+        }
+
+        // Give any new seats to the clients in line.
+        self.admit_waiting_clients();
+    }
+}
+
+impl Handler<DeleteRoom> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: DeleteRoom, ctx: &mut Context<Self>) {
+        if !self.get_role(&msg.id).allows(Permission::ManageRoom) {
+            msg.addr.do_send(WsMessage::Text(
+                Output::Error(OutputError::Forbidden).to_room_text(self.id),
+            ));
+            return;
+        }
+
+        for (_, handle) in self.detached.drain() {
+            ctx.cancel_future(handle);
+        }
+
+        // Take everyone with a connection out of the room.
+        let output = Output::RoomDeleted(RoomDeletedOutput::new(self.id));
+        let text = output.to_room_text(self.id);
+        for (_, session) in self.sessions.drain() {
+            session
+                .addr
+                .do_send(WsMessage::Removed(self.id, text.clone()));
+        }
+        if !self.clients.contains_key(&msg.id) {
+            msg.addr.do_send(WsMessage::Text(text));
+        }
+
+        self.clients.clear();
+        self.typing_clients.clear();
+        self.waiting_clients.clear();
+
+        self.close(ctx);
+    }
+}
+
+impl Handler<CreateInvite> for ChatRoom {
+    type Result = ();
+
+    fn handle(&mut self, msg: CreateInvite, _: &mut Context<Self>) {
+        // Moderators don't have to be in the room to invite others.
+        if !self.get_role(&msg.id).allows(Permission::Moderate) {
+            msg.addr.do_send(WsMessage::Text(
+                Output::Error(OutputError::Forbidden).to_room_text(self.id),
+            ));
+            return;
+        }
+
+        let now = Utc::now();
+        self.expire_moderation(now);
+
+        let expires_at = match msg.expires_in {
+            Some(expires_in) => match chrono::Duration::from_std(expires_in)
+                .ok()
+                .and_then(|expires_in| now.checked_add_signed(expires_in))
+            {
+                Some(expires_at) => Some(expires_at),
+                None => {
+                    msg.addr.do_send(WsMessage::Text(
+                        Output::Error(OutputError::InvalidInput).to_room_text(self.id),
+                    ));
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        let code = new_invite_code();
+        self.invites.insert(code.clone(), expires_at);
+
+        msg.addr.do_send(WsMessage::Text(
+            Output::InviteCreated(InviteCreatedOutput::new(self.id, code, expires_at))
+                .to_room_text(self.id),
+        ));
+    }
+}
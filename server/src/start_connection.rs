@@ -1,6 +1,9 @@
+use crate::config::Config;
 use crate::lobby::Lobby;
+use crate::rate_limit::{IpRateLimits, RateLimiter};
 use crate::ws::ChatWebsocket;
 use actix::Addr;
+use actix_http::ws::Codec;
 use actix_web::{get, web::Data, web::Payload, Error, HttpRequest, HttpResponse};
 use actix_web_actors::ws;
 
@@ -9,9 +12,38 @@ pub async fn start_connection(
     req: HttpRequest,
     stream: Payload,
     srv: Data<Addr<Lobby>>,
+    config: Data<Config>,
+    ip_rate_limits: Data<IpRateLimits>,
 ) -> Result<HttpResponse, Error> {
-    let ws = ChatWebsocket::new(srv.get_ref().clone());
+    // Refuse the upgrade unless the client is who it says it is (or anonymous
+    // clients are let in).
+    let identity = match config.auth.authenticate(&req) {
+        Ok(identity) => identity,
+        Err(e) => {
+            println!("Refused WebSocket connection: {}", e);
+            return Ok(HttpResponse::Unauthorized().finish());
+        }
+    };
 
-    let resp = ws::start(ws, &req, stream)?;
-    Ok(resp)
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    let rate_limiter = RateLimiter::new(
+        config.rate_limits.clone(),
+        ip,
+        ip_rate_limits.get_ref().clone(),
+    );
+
+    let ws = ChatWebsocket::new(
+        srv.get_ref().clone(),
+        identity,
+        ip,
+        config.timeouts.clone(),
+        config.limits.clone(),
+        rate_limiter,
+    );
+
+    // Frames larger than the limit are refused before they are buffered.
+    let codec = Codec::new().max_size(config.limits.max_frame_size);
+
+    let mut resp = ws::handshake(&req)?;
+    Ok(resp.streaming(ws::WebsocketContext::with_codec(ws, stream, codec)))
 }
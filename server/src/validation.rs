@@ -0,0 +1,192 @@
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::skeleton;
+
+/// Maximum length (in characters) of a username.
+pub const MAX_USERNAME_LENGTH: usize = 32;
+
+/// Maximum length (in characters) of a nonce that a message is posted with.
+pub const MAX_NONCE_LENGTH: usize = 64;
+
+/// Maximum length (in characters) of the reason a moderator gives.
+pub const MAX_REASON_LENGTH: usize = 200;
+
+/// Maximum length (in characters) of a room name.
+pub const MAX_ROOM_NAME_LENGTH: usize = 64;
+
+/// Maximum length (in characters) of a room topic.
+pub const MAX_ROOM_TOPIC_LENGTH: usize = 200;
+
+/// Maximum length (in characters) of a room password.
+pub const MAX_PASSWORD_LENGTH: usize = 128;
+
+/// Checks that a username is something other clients can display and tell
+/// apart: non-empty, at most `MAX_USERNAME_LENGTH` characters, no control
+/// characters, and no whitespace other than single spaces between words.
+pub fn is_valid_username(username: &str) -> bool {
+    let length = username.chars().count();
+
+    length > 0
+        && length <= MAX_USERNAME_LENGTH
+        && username.trim() == username
+        && !username.contains("  ")
+        && username
+            .chars()
+            .all(|c| !c.is_control() && (c == ' ' || !c.is_whitespace()))
+}
+
+/// Checks that a room name is non-empty, at most `MAX_ROOM_NAME_LENGTH`
+/// characters, has no surrounding whitespace and no control characters.
+pub fn is_valid_room_name(name: &str) -> bool {
+    let length = name.chars().count();
+
+    length > 0
+        && length <= MAX_ROOM_NAME_LENGTH
+        && name.trim() == name
+        && !name.chars().any(char::is_control)
+}
+
+/// Checks that a room topic is non-empty, at most `MAX_ROOM_TOPIC_LENGTH`
+/// characters, has no surrounding whitespace and no control characters.
+pub fn is_valid_room_topic(topic: &str) -> bool {
+    let length = topic.chars().count();
+
+    length > 0
+        && length <= MAX_ROOM_TOPIC_LENGTH
+        && topic.trim() == topic
+        && !topic.chars().any(char::is_control)
+}
+
+/// Checks that a room password is non-empty, at most `MAX_PASSWORD_LENGTH`
+/// characters and has no control characters.
+pub fn is_valid_password(password: &str) -> bool {
+    let length = password.chars().count();
+
+    length > 0 && length <= MAX_PASSWORD_LENGTH && !password.chars().any(char::is_control)
+}
+
+/// Returns the key used to compare usernames for uniqueness. Two usernames
+/// with the same key are considered to be the same name: the key is
+/// case-insensitive, Unicode-normalized (NFKC) and maps look-alike characters
+/// to a common skeleton (UTS #39), so "Joel", "joel" and "јоеl" (Cyrillic)
+/// all collide.
+pub fn username_key(username: &str) -> String {
+    let folded: String = username.nfkc().collect::<String>().to_lowercase();
+    skeleton(&folded).collect()
+}
+
+/// Normalizes a message body so that every client shows it the same way: line
+/// endings become `\n`, the text is Unicode-normalized (NFC) and surrounding
+/// whitespace is trimmed. Returns `None` if what is left is empty, longer than
+/// `max_length` characters or has control characters other than newlines and
+/// tabs.
+pub fn normalize_message_body(body: &str, max_length: usize) -> Option<String> {
+    let body: String = body
+        .replace("\r\n", "\n")
+        .replace('\r', "\n")
+        .nfc()
+        .collect();
+    let body = body.trim();
+
+    let valid = !body.is_empty()
+        && body.chars().count() <= max_length
+        && body
+            .chars()
+            .all(|c| !c.is_control() || c == '\n' || c == '\t');
+
+    if valid {
+        Some(body.to_string())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn look_alike_usernames_share_a_key() {
+        assert_eq!(username_key("Joel"), username_key("joel"));
+        // Cyrillic "ј" and "о".
+        assert_eq!(username_key("joel"), username_key("\u{458}\u{43e}el"));
+        // Fullwidth letters are folded by NFKC.
+        assert_eq!(username_key("joel"), username_key("\u{ff4a}oel"));
+        // "l" and "1" look alike.
+        assert_eq!(username_key("joel"), username_key("joe1"));
+    }
+
+    #[test]
+    fn different_usernames_have_different_keys() {
+        assert_ne!(username_key("joel"), username_key("joe"));
+        assert_ne!(username_key("joel"), username_key("jo el"));
+    }
+
+    #[test]
+    fn valid_usernames() {
+        assert!(is_valid_username("joel"));
+        assert!(is_valid_username("Joel Smith"));
+        assert!(is_valid_username("\u{458}\u{43e}el"));
+        assert!(is_valid_username(&"a".repeat(MAX_USERNAME_LENGTH)));
+    }
+
+    #[test]
+    fn invalid_usernames() {
+        assert!(!is_valid_username(""));
+        assert!(!is_valid_username(&"a".repeat(MAX_USERNAME_LENGTH + 1)));
+        assert!(!is_valid_username(" joel"));
+        assert!(!is_valid_username("joel "));
+        assert!(!is_valid_username("joel  smith"));
+        assert!(!is_valid_username("joel\tsmith"));
+        assert!(!is_valid_username("joel\u{a0}smith"));
+        assert!(!is_valid_username("joel\u{7}"));
+    }
+
+    #[test]
+    fn empty_message_bodies_are_invalid() {
+        assert_eq!(normalize_message_body("", 10), None);
+        assert_eq!(normalize_message_body(" \n\t\r\n ", 10), None);
+    }
+
+    #[test]
+    fn message_bodies_with_control_characters_are_invalid() {
+        assert_eq!(normalize_message_body("hi\u{7}", 10), None);
+        assert_eq!(normalize_message_body("hi\u{0}there", 10), None);
+        assert_eq!(normalize_message_body("hi\u{1b}[2J", 10), None);
+        // Newlines and tabs are kept.
+        assert_eq!(
+            normalize_message_body("hi\n\tthere", 10),
+            Some("hi\n\tthere".to_string())
+        );
+    }
+
+    #[test]
+    fn message_length_is_counted_in_characters() {
+        // Five characters, but ten bytes.
+        assert_eq!(
+            normalize_message_body("\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}", 5),
+            Some("\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}".to_string())
+        );
+        assert_eq!(
+            normalize_message_body("\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}", 5),
+            None
+        );
+        // Whitespace that is trimmed doesn't count.
+        assert_eq!(
+            normalize_message_body("  hello  ", 5),
+            Some("hello".to_string())
+        );
+    }
+
+    #[test]
+    fn message_bodies_are_normalized() {
+        assert_eq!(
+            normalize_message_body("  one\r\ntwo\rthree\n ", 20),
+            Some("one\ntwo\nthree".to_string())
+        );
+        // "e" followed by a combining acute accent becomes a single "\u{e9}".
+        assert_eq!(
+            normalize_message_body("caf\u{65}\u{301}", 4),
+            Some("caf\u{e9}".to_string())
+        );
+    }
+}
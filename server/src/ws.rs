@@ -1,47 +1,90 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use actix::prelude::*;
-use actix_web_actors::ws;
+use actix_web::web;
+use actix_web_actors::ws::{self, CloseCode, CloseReason};
 use uuid::Uuid;
 
+use crate::auth::Identity;
+use crate::config::{LimitConfig, TimeoutConfig};
+use crate::credentials::hash_password;
 use crate::lobby::Lobby;
-use crate::messages::{ClientActorMessage, Connect, Disconnect, Join, Typing, WsMessage};
+use crate::messages::{
+    ClaimSeat, ClientActorMessage, Connect, CreateInvite, CreateRoom, Delete, DeleteRoom, Direct,
+    DirectHistory, Disconnect, DroppedOutputs, Edit, FindRoom, Grant, History, Join, Moderate,
+    ModerationAction, Resume, Typing, Unsubscribe, UpdateRoom, WsMessage,
+};
 use crate::proto::*;
-
-// How often heartbeat pings are sent.
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
-
-// How long before lack of client response causes a timeout.
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+use crate::rate_limit::{InputKind, RateLimiter, Verdict};
+use crate::rooms::ChatRoom;
+use crate::validation::{
+    is_valid_password, is_valid_room_name, is_valid_room_topic, is_valid_username,
+    normalize_message_body, MAX_NONCE_LENGTH, MAX_REASON_LENGTH,
+};
 
 // WebSocket connections is a "long running" connection,
 // so we want to handle it with an "actor"?
 pub struct ChatWebsocket {
-    room: Uuid,
+    // The rooms the client is in (or waiting for a seat in), by id.
+    rooms: HashMap<Uuid, Addr<ChatRoom>>,
     lobby_addr: Addr<Lobby>,
     hb: Instant,
     id: Uuid,
+    // Set if the client authenticated when connecting.
+    identity: Option<Identity>,
+    // The address the client connected from, if known.
+    ip: Option<IpAddr>,
+    // Whether the client's seats are held for it to resume once the
+    // connection ends. They are when the connection is lost, but not when
+    // either side closes it on purpose.
+    resumable: bool,
+    // Outputs that rooms left out because the client couldn't keep up, and
+    // how many of them the client has been told about.
+    dropped: DroppedOutputs,
+    reported_dropped: u64,
+    timeouts: TimeoutConfig,
+    limits: LimitConfig,
+    rate_limiter: RateLimiter,
 }
 
 impl ChatWebsocket {
-    pub fn new(lobby: Addr<Lobby>) -> Self {
+    pub fn new(
+        lobby: Addr<Lobby>,
+        identity: Option<Identity>,
+        ip: Option<IpAddr>,
+        timeouts: TimeoutConfig,
+        limits: LimitConfig,
+        rate_limiter: RateLimiter,
+    ) -> Self {
         ChatWebsocket {
-            room: Uuid::new_v4(),
+            rooms: HashMap::new(),
             lobby_addr: lobby,
             hb: Instant::now(),
-            id: Uuid::new_v4(),
+            // Authenticated clients keep their id across connections.
+            id: identity
+                .as_ref()
+                .map_or_else(Uuid::new_v4, |identity| identity.id),
+            identity,
+            ip,
+            resumable: true,
+            dropped: DroppedOutputs::default(),
+            reported_dropped: 0,
+            timeouts,
+            limits,
+            rate_limiter,
         }
     }
 
     fn hb(&self, ctx: &mut <Self as Actor>::Context) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
+        ctx.run_interval(self.timeouts.heartbeat_interval, |act, ctx| {
+            if Instant::now().duration_since(act.hb) > act.timeouts.client_timeout {
                 println!("WebSocket client heartbeat failed, disconnecting.");
 
-                act.lobby_addr.do_send(Disconnect {
-                    self_id: act.id,
-                    room_id: act.room,
-                });
+                act.leave_rooms(true, ctx);
 
                 ctx.stop();
 
@@ -49,8 +92,615 @@ impl ChatWebsocket {
             }
 
             ctx.ping(b"");
+            act.report_dropped(ctx);
         });
     }
+
+    // Tells the client if outputs were left out for it since it was last told.
+    fn report_dropped(&mut self, ctx: &mut <Self as Actor>::Context) {
+        let dropped = self.dropped.load(Ordering::Relaxed);
+        if dropped > self.reported_dropped {
+            self.reported_dropped = dropped;
+            ctx.text(Output::OutputsDropped(OutputsDroppedOutput::new(dropped)).to_text());
+        }
+    }
+
+    // Tells a room that the client has left it, if the client is in it.
+    // `resumable` tells whether the client just lost its connection, rather
+    // than leaving on purpose.
+    fn leave_room(&mut self, room_id: Uuid, resumable: bool, ctx: &mut <Self as Actor>::Context) {
+        if let Some(room) = self.rooms.remove(&room_id) {
+            room.do_send(Disconnect {
+                addr: ctx.address().recipient(),
+                self_id: self.id,
+                resumable,
+            });
+        }
+    }
+
+    // Tells every room the client is in that it has left.
+    fn leave_rooms(&mut self, resumable: bool, ctx: &mut <Self as Actor>::Context) {
+        for (_, room) in self.rooms.drain() {
+            room.do_send(Disconnect {
+                addr: ctx.address().recipient(),
+                self_id: self.id,
+                resumable,
+            });
+        }
+    }
+
+    // Returns the room with the given id if the client is in it, and otherwise
+    // lets the client know it isn't.
+    fn joined_room(
+        &self,
+        room_id: Uuid,
+        ctx: &mut <Self as Actor>::Context,
+    ) -> Option<Addr<ChatRoom>> {
+        let room = self.rooms.get(&room_id).cloned();
+        if room.is_none() {
+            self.send_room_error(room_id, OutputError::NotJoined, ctx);
+        }
+        room
+    }
+
+    // Asks a room found by the lobby to let the client in, with the password
+    // or invite from the join input. Other messages are held back until the
+    // room has answered.
+    fn join_room(
+        &mut self,
+        room: Addr<ChatRoom>,
+        username: String,
+        inp: JoinInput,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        let room_id = inp.room;
+        room.send(Join {
+            addr: ctx.address().recipient(),
+            self_id: self.id,
+            username,
+            wait: inp.wait,
+            ip: self.ip,
+            password: inp.password,
+            invite: inp.invite,
+            dropped: Arc::clone(&self.dropped),
+        })
+        .into_actor(self)
+        .then(move |res, act, ctx| {
+            match res {
+                Ok(true) => {
+                    act.rooms.insert(room_id, room);
+                }
+                Ok(false) => (),
+                // The room was deleted in the meantime.
+                Err(_) => act.send_room_error(room_id, OutputError::RoomNotFound, ctx),
+            }
+            fut::ready(())
+        })
+        .wait(ctx);
+    }
+
+    // Asks a room to give the seat of session `id` to this connection. Takes
+    // on the identity of the session once the room has handed it over.
+    fn resume_session(
+        &mut self,
+        id: Uuid,
+        room_id: Uuid,
+        room: Addr<ChatRoom>,
+        last_seen: Option<MessageId>,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        room.send(Resume {
+            addr: ctx.address().recipient(),
+            self_id: id,
+            last_seen,
+            ip: self.ip,
+            dropped: Arc::clone(&self.dropped),
+        })
+        .into_actor(self)
+        .then(move |res, act, _| {
+            if let Ok(true) = res {
+                act.id = id;
+                act.rooms.insert(room_id, room);
+            }
+            fut::ready(())
+        })
+        .wait(ctx);
+    }
+
+    // Hashes a password and hands the hash to `then`, or `None` if hashing
+    // failed. Hashing takes long enough to hold up every connection on the
+    // thread, so it is done on the blocking thread pool. Other inputs from
+    // the client wait until it is done.
+    fn hash_password<F>(&mut self, password: String, then: F, ctx: &mut <Self as Actor>::Context)
+    where
+        F: FnOnce(&mut Self, Option<String>, &mut <Self as Actor>::Context) + 'static,
+    {
+        web::block(move || hash_password(&password))
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                then(act, res.ok().flatten(), ctx);
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    // Sends an error back to the client.
+    fn send_error(&self, error: OutputError, ctx: &mut <Self as Actor>::Context) {
+        ctx.text(Output::Error(error).to_text());
+    }
+
+    // Sends an error about an input for one of the rooms back to the client,
+    // tagged with the room.
+    fn send_room_error(
+        &self,
+        room_id: Uuid,
+        error: OutputError,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        ctx.text(Output::Error(error).to_room_text(room_id));
+    }
+
+    // Drops inputs from clients that send them too fast, and disconnects
+    // clients that don't slow down.
+    fn check_rate(&mut self, input: &Input, ctx: &mut <Self as Actor>::Context) -> bool {
+        match self
+            .rate_limiter
+            .check(InputKind::of(input), Instant::now())
+        {
+            Verdict::Allowed => true,
+            Verdict::Limited(retry_after) => {
+                // Round up, so that clients waiting as long as they are told
+                // to aren't limited again.
+                let retry_after = (retry_after + Duration::from_micros(999)).as_millis() as u64;
+                self.send_error(OutputError::RateLimited { retry_after }, ctx);
+                false
+            }
+            Verdict::Disconnect => {
+                println!("WebSocket client keeps flooding, disconnecting.");
+                self.resumable = false;
+                ctx.close(Some(CloseReason {
+                    code: CloseCode::Policy,
+                    description: Some("Too many messages".to_string()),
+                }));
+                ctx.stop();
+                false
+            }
+        }
+    }
+
+    // Asks the room to carry out a moderation. The room checks that the client
+    // is a moderator.
+    fn moderate(
+        &mut self,
+        room_id: Uuid,
+        user: Uuid,
+        action: ModerationAction,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        let room = match self.joined_room(room_id, ctx) {
+            Some(room) => room,
+            None => return,
+        };
+
+        room.do_send(Moderate {
+            id: self.id,
+            user,
+            action,
+        });
+    }
+
+    // Asks the room to change the role of a user, or to put it back to the
+    // default role if `role` is `None`. The room checks that the client may.
+    fn grant(
+        &mut self,
+        room_id: Uuid,
+        user: Uuid,
+        role: Option<Role>,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        let room = match self.joined_room(room_id, ctx) {
+            Some(room) => room,
+            None => return,
+        };
+
+        room.do_send(Grant {
+            id: self.id,
+            user,
+            role,
+        });
+    }
+
+    // Validates and forwards a parsed input from the client.
+    fn handle_input(&mut self, input: Input, ctx: &mut <Self as Actor>::Context) {
+        if !self.check_rate(&input, ctx) {
+            return;
+        }
+
+        match input {
+            Input::Join(inp) => {
+                // Authenticated clients go by the name in their token.
+                let username = match &self.identity {
+                    Some(identity) => identity.name.clone(),
+                    None => inp.username.clone(),
+                };
+
+                if !is_valid_username(&username) {
+                    return self.send_room_error(inp.room, OutputError::InvalidName, ctx);
+                }
+
+                let invalid = inp.max_clients == Some(0)
+                    || inp
+                        .password
+                        .as_deref()
+                        .is_some_and(|password| !is_valid_password(password));
+                if invalid {
+                    return self.send_room_error(inp.room, OutputError::InvalidInput, ctx);
+                }
+
+                // Joining a room the client is already in joins it afresh.
+                self.leave_room(inp.room, false, ctx);
+
+                // Only consider the client to be in the room once the room has
+                // let it in (or into its waiting queue). Other messages are
+                // held back until the lobby and the room have answered.
+                self.lobby_addr
+                    .send(FindRoom {
+                        addr: ctx.address().recipient(),
+                        room_id: inp.room,
+                        self_id: self.id,
+                        username: username.clone(),
+                        max_clients: inp.max_clients,
+                    })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        match res {
+                            Ok(Some(room)) => act.join_room(room, username, inp, ctx),
+                            Ok(None) => {
+                                act.send_room_error(inp.room, OutputError::RoomNotFound, ctx)
+                            }
+                            Err(_) => act.send_room_error(inp.room, OutputError::Internal, ctx),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            Input::Leave(inp) => {
+                if !self.rooms.contains_key(&inp.room) {
+                    return self.send_room_error(inp.room, OutputError::NotJoined, ctx);
+                }
+
+                self.leave_room(inp.room, false, ctx);
+            }
+            Input::Post(inp) => {
+                let room = match self.joined_room(inp.room, ctx) {
+                    Some(room) => room,
+                    None => return,
+                };
+
+                let body =
+                    match normalize_message_body(&inp.message, self.limits.max_message_length) {
+                        Some(body) => body,
+                        None => {
+                            return self.send_room_error(
+                                inp.room,
+                                OutputError::InvalidMessageBody,
+                                ctx,
+                            )
+                        }
+                    };
+
+                let invalid_nonce = inp.nonce.as_ref().is_some_and(|nonce| {
+                    nonce.is_empty() || nonce.chars().count() > MAX_NONCE_LENGTH
+                });
+                if invalid_nonce {
+                    return self.send_room_error(inp.room, OutputError::InvalidInput, ctx);
+                }
+
+                room.do_send(ClientActorMessage {
+                    id: self.id,
+                    msg: body,
+                    nonce: inp.nonce,
+                });
+            }
+            Input::Typing(inp) => {
+                let room = match self.joined_room(inp.room, ctx) {
+                    Some(room) => room,
+                    None => return,
+                };
+
+                room.do_send(Typing {
+                    id: self.id,
+                    status: inp.status,
+                });
+            }
+            Input::Resume(inp) => {
+                // The lobby knows which session and room the token is for. A
+                // client that is signed in, or already in rooms, has a session
+                // of its own, and can only get back seats of that session.
+                let self_id =
+                    Some(self.id).filter(|_| self.identity.is_some() || !self.rooms.is_empty());
+                let last_seen = inp.last_seen;
+                self.lobby_addr
+                    .send(ClaimSeat {
+                        token: inp.token,
+                        self_id,
+                    })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        match res {
+                            Ok(Some((id, room_id, room))) if !act.rooms.contains_key(&room_id) => {
+                                act.resume_session(id, room_id, room, last_seen, ctx)
+                            }
+                            _ => act.send_error(OutputError::InvalidResumeToken, ctx),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            Input::Edit(inp) => {
+                let room = match self.joined_room(inp.room, ctx) {
+                    Some(room) => room,
+                    None => return,
+                };
+
+                let body =
+                    match normalize_message_body(&inp.message, self.limits.max_message_length) {
+                        Some(body) => body,
+                        None => {
+                            return self.send_room_error(
+                                inp.room,
+                                OutputError::InvalidMessageBody,
+                                ctx,
+                            )
+                        }
+                    };
+
+                room.do_send(Edit {
+                    id: self.id,
+                    message_id: inp.id,
+                    body,
+                });
+            }
+            Input::Delete(inp) => {
+                let room = match self.joined_room(inp.room, ctx) {
+                    Some(room) => room,
+                    None => return,
+                };
+
+                room.do_send(Delete {
+                    id: self.id,
+                    message_id: inp.id,
+                });
+            }
+            Input::Direct(inp) => {
+                if self.rooms.is_empty() {
+                    return self.send_error(OutputError::NotJoined, ctx);
+                }
+
+                if inp.to == self.id {
+                    return self.send_error(OutputError::InvalidInput, ctx);
+                }
+
+                let body =
+                    match normalize_message_body(&inp.message, self.limits.max_message_length) {
+                        Some(body) => body,
+                        None => return self.send_error(OutputError::InvalidMessageBody, ctx),
+                    };
+
+                self.lobby_addr.do_send(Direct {
+                    id: self.id,
+                    to: inp.to,
+                    body,
+                });
+            }
+            Input::History(inp) => {
+                // Direct conversations are kept by the lobby.
+                let room_id = match (inp.room, inp.with) {
+                    (_, Some(with)) => {
+                        if inp.limit == Some(0) {
+                            return self.send_error(OutputError::InvalidInput, ctx);
+                        }
+
+                        return self.lobby_addr.do_send(DirectHistory {
+                            addr: ctx.address().recipient(),
+                            id: self.id,
+                            with,
+                            before: inp.before,
+                            after: inp.after,
+                            limit: inp.limit,
+                        });
+                    }
+                    (Some(room_id), None) => room_id,
+                    (None, None) => return self.send_error(OutputError::InvalidInput, ctx),
+                };
+
+                let room = match self.joined_room(room_id, ctx) {
+                    Some(room) => room,
+                    None => return,
+                };
+
+                if inp.limit == Some(0) {
+                    return self.send_room_error(room_id, OutputError::InvalidInput, ctx);
+                }
+
+                room.do_send(History {
+                    id: self.id,
+                    before: inp.before,
+                    after: inp.after,
+                    limit: inp.limit,
+                });
+            }
+            Input::Kick(inp) => {
+                let reason = match normalize_reason(inp.reason) {
+                    Ok(reason) => reason,
+                    Err(error) => return self.send_room_error(inp.room, error, ctx),
+                };
+
+                self.moderate(inp.room, inp.user, ModerationAction::Kick { reason }, ctx);
+            }
+            Input::Ban(inp) => {
+                let reason = match normalize_reason(inp.reason) {
+                    Ok(reason) => reason,
+                    Err(error) => return self.send_room_error(inp.room, error, ctx),
+                };
+
+                if inp.duration == Some(0) {
+                    return self.send_room_error(inp.room, OutputError::InvalidInput, ctx);
+                }
+
+                let action = ModerationAction::Ban {
+                    reason,
+                    duration: inp.duration.map(Duration::from_secs),
+                    ip: inp.ip,
+                };
+                self.moderate(inp.room, inp.user, action, ctx);
+            }
+            Input::Unban(inp) => self.moderate(inp.room, inp.user, ModerationAction::Unban, ctx),
+            Input::Mute(inp) => {
+                let reason = match normalize_reason(inp.reason) {
+                    Ok(reason) => reason,
+                    Err(error) => return self.send_room_error(inp.room, error, ctx),
+                };
+
+                if inp.duration == Some(0) {
+                    return self.send_room_error(inp.room, OutputError::InvalidInput, ctx);
+                }
+
+                let action = ModerationAction::Mute {
+                    reason,
+                    duration: inp.duration.map(Duration::from_secs),
+                };
+                self.moderate(inp.room, inp.user, action, ctx);
+            }
+            Input::Unmute(inp) => self.moderate(inp.room, inp.user, ModerationAction::Unmute, ctx),
+            Input::Grant(inp) => self.grant(inp.room, inp.user, Some(inp.role), ctx),
+            Input::Revoke(inp) => self.grant(inp.room, inp.user, None, ctx),
+            Input::CreateRoom(mut inp) => {
+                let invalid = !is_valid_room_name(&inp.name)
+                    || inp
+                        .topic
+                        .as_deref()
+                        .is_some_and(|topic| !is_valid_room_topic(topic))
+                    || inp.max_clients == Some(0)
+                    || inp
+                        .password
+                        .as_deref()
+                        .is_some_and(|password| !is_valid_password(password));
+                if invalid {
+                    return self.send_error(OutputError::InvalidInput, ctx);
+                }
+
+                // Anonymous users could create persistent rooms without end.
+                if inp.lifecycle == Lifecycle::Persistent && self.identity.is_none() {
+                    return self.send_error(OutputError::Forbidden, ctx);
+                }
+
+                let password = inp.password.take();
+                let create =
+                    move |act: &mut Self, password_hash, ctx: &mut ws::WebsocketContext<Self>| {
+                        act.lobby_addr.do_send(CreateRoom {
+                            addr: ctx.address().recipient(),
+                            id: act.id,
+                            name: inp.name,
+                            topic: inp.topic,
+                            max_clients: inp.max_clients,
+                            lifecycle: inp.lifecycle,
+                            password_hash,
+                            private: inp.private,
+                            invite_only: inp.invite_only,
+                        });
+                    };
+
+                // Only the hash of the password is kept.
+                match password {
+                    Some(password) => self.hash_password(
+                        password,
+                        move |act, hash, ctx| match hash {
+                            Some(hash) => create(act, Some(hash), ctx),
+                            None => act.send_error(OutputError::Internal, ctx),
+                        },
+                        ctx,
+                    ),
+                    None => create(self, None, ctx),
+                }
+            }
+            Input::UpdateRoom(mut inp) => {
+                // An empty topic or password removes the topic or password.
+                let invalid = inp
+                    .name
+                    .as_deref()
+                    .is_some_and(|name| !is_valid_room_name(name))
+                    || inp
+                        .topic
+                        .as_deref()
+                        .is_some_and(|topic| !topic.is_empty() && !is_valid_room_topic(topic))
+                    || inp.max_clients == Some(0)
+                    || inp.password.as_deref().is_some_and(|password| {
+                        !password.is_empty() && !is_valid_password(password)
+                    });
+                if invalid {
+                    return self.send_room_error(inp.room, OutputError::InvalidInput, ctx);
+                }
+
+                let password = inp.password.take();
+                let room_id = inp.room;
+                let update =
+                    move |act: &mut Self, password_hash, ctx: &mut ws::WebsocketContext<Self>| {
+                        act.lobby_addr.do_send(UpdateRoom {
+                            addr: ctx.address().recipient(),
+                            id: act.id,
+                            room_id: inp.room,
+                            name: inp.name,
+                            topic: inp.topic,
+                            max_clients: inp.max_clients,
+                            password_hash,
+                            private: inp.private,
+                            invite_only: inp.invite_only,
+                        });
+                    };
+
+                match password {
+                    Some(password) if password.is_empty() => update(self, Some(None), ctx),
+                    Some(password) => self.hash_password(
+                        password,
+                        move |act, hash, ctx| match hash {
+                            Some(hash) => update(act, Some(Some(hash)), ctx),
+                            None => act.send_room_error(room_id, OutputError::Internal, ctx),
+                        },
+                        ctx,
+                    ),
+                    None => update(self, None, ctx),
+                }
+            }
+            Input::DeleteRoom(inp) => self.lobby_addr.do_send(DeleteRoom {
+                addr: ctx.address().recipient(),
+                id: self.id,
+                room_id: inp.room,
+            }),
+            Input::CreateInvite(inp) => {
+                if inp.expires_in == Some(0) {
+                    return self.send_room_error(inp.room, OutputError::InvalidInput, ctx);
+                }
+
+                self.lobby_addr.do_send(CreateInvite {
+                    addr: ctx.address().recipient(),
+                    id: self.id,
+                    room_id: inp.room,
+                    expires_in: inp.expires_in.map(Duration::from_secs),
+                });
+            }
+        }
+    }
+}
+
+// Normalizes the optional reason a moderator gave, like a message body.
+fn normalize_reason(reason: Option<String>) -> Result<Option<String>, OutputError> {
+    match reason {
+        Some(reason) => normalize_message_body(&reason, MAX_REASON_LENGTH)
+            .map(Some)
+            .ok_or(OutputError::InvalidInput),
+        None => Ok(None),
+    }
 }
 
 impl Actor for ChatWebsocket {
@@ -58,6 +708,10 @@ impl Actor for ChatWebsocket {
 
     // Called when a WebSocket client connection starts.
     fn started(&mut self, ctx: &mut Self::Context) {
+        // Outputs queue up in the mailbox while the client isn't reading them,
+        // rooms stop queueing more once it is full.
+        ctx.set_mailbox_capacity(self.limits.outbound_queue_size);
+
         self.hb(ctx);
 
         self.lobby_addr.do_send(Connect {
@@ -66,10 +720,18 @@ impl Actor for ChatWebsocket {
     }
 
     // Called when a WebSocket client connection has ended.
-    fn stopping(&mut self, _ctx: &mut Self::Context) -> Running {
-        self.lobby_addr.do_send(Disconnect {
-            self_id: self.id,
-            room_id: self.room,
+    fn stopping(&mut self, ctx: &mut Self::Context) -> Running {
+        let dropped = self.dropped.load(Ordering::Relaxed);
+        if dropped > 0 {
+            println!(
+                "Left out {} outputs for client {} that couldn't keep up.",
+                dropped, self.id
+            );
+        }
+
+        self.leave_rooms(self.resumable, ctx);
+        self.lobby_addr.do_send(Unsubscribe {
+            addr: ctx.address().recipient(),
         });
         Running::Stop
     }
@@ -78,13 +740,6 @@ impl Actor for ChatWebsocket {
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatWebsocket {
     // Called when a message is received from a WebSocket client.
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
-        // Print out the message if it's not a ping/pong.
-        match msg {
-            Ok(ws::Message::Pong(_)) => (),
-            Ok(ws::Message::Ping(_)) => (),
-            _ => println!("WS: {:?}", msg),
-        }
-
         // Process websocket messages
         match msg {
             Ok(ws::Message::Ping(msg)) => {
@@ -96,58 +751,54 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatWebsocket {
             }
             Ok(ws::Message::Binary(bin)) => ctx.binary(bin),
             Ok(ws::Message::Close(reason)) => {
+                // The client is leaving for good.
+                self.resumable = false;
                 ctx.close(reason);
                 ctx.stop();
             }
             Ok(ws::Message::Continuation(_)) => {
+                // Fragmented messages aren't supported.
+                self.resumable = false;
+                ctx.close(Some(CloseReason {
+                    code: CloseCode::Unsupported,
+                    description: Some("Fragmented messages are not supported".to_string()),
+                }));
                 ctx.stop();
             }
             Ok(ws::Message::Nop) => (),
             Ok(ws::Message::Text(text)) => {
-                // Parse the message as json.
-                let result: serde_json::Result<Input> = serde_json::from_str(&text);
-
-                // Figure out what message the client has sent.
-                if let Ok(input) = result {
-                    match input {
-                        Input::Join(inp) => {
-                            self.room = inp.room;
-                            self.lobby_addr.do_send(Join {
-                                addr: ctx.address().recipient(),
-                                lobby_id: self.room,
-                                self_id: self.id,
-                                username: inp.username,
-                            });
-                        }
-                        Input::Leave => {
-                            self.lobby_addr.do_send(Disconnect {
-                                self_id: self.id,
-                                room_id: self.room,
-                            });
-
-                            // Send the updates rooms to the client.
-                            self.lobby_addr.do_send(Connect {
-                                addr: ctx.address().recipient(),
-                            });
-                        }
-                        Input::Post(inp) => self.lobby_addr.do_send(ClientActorMessage {
-                            id: self.id,
-                            msg: inp.message,
-                            room_id: self.room,
-                        }),
-                        Input::Typing(status) => {
-                            self.lobby_addr.do_send(Typing {
-                                id: self.id,
-                                room_id: self.room,
-                                status,
-                            });
-                        }
-                    };
-                } else {
-                    // TODO: Send error message (and close connection??).
+                // Parse the message as json and figure out what message the
+                // client has sent.
+                match serde_json::from_str::<Input>(&text) {
+                    Ok(input) => self.handle_input(input, ctx),
+                    Err(_) => self.send_error(OutputError::InvalidInput, ctx),
                 }
             }
-            Err(e) => panic!(e), // TODO: Change this panic to something else (log and disconnect?).
+            Err(ws::ProtocolError::Overflow) => {
+                println!("WebSocket frame too large, disconnecting.");
+                self.resumable = false;
+                ctx.close(Some(CloseReason {
+                    code: CloseCode::Size,
+                    description: Some("Message too large".to_string()),
+                }));
+                ctx.stop();
+            }
+            Err(ws::ProtocolError::Io(e)) => {
+                // The connection was lost, the client may resume its seats.
+                println!("WebSocket connection lost: {}", e);
+                ctx.stop();
+            }
+            Err(e) => {
+                // The connection can't be trusted anymore after a protocol
+                // error, so only this client is disconnected, for good.
+                println!("WebSocket protocol error, disconnecting: {}", e);
+                self.resumable = false;
+                ctx.close(Some(CloseReason {
+                    code: CloseCode::Protocol,
+                    description: Some(e.to_string()),
+                }));
+                ctx.stop();
+            }
         }
     }
 }
@@ -156,6 +807,21 @@ impl Handler<WsMessage> for ChatWebsocket {
     type Result = ();
 
     fn handle(&mut self, msg: WsMessage, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+        match msg {
+            // The text shared by every recipient is framed as it is, without
+            // a copy per client.
+            WsMessage::Text(text) => ctx.text(text),
+            WsMessage::Removed(room_id, text) => {
+                // The room has already dealt with the session.
+                self.rooms.remove(&room_id);
+                ctx.text(text);
+            }
+            WsMessage::Close(reason) => {
+                // The room sending it has already dealt with the session, the
+                // other rooms are left once the connection stops.
+                ctx.close(reason);
+                ctx.stop();
+            }
+        }
     }
 }
@@ -0,0 +1,275 @@
+//! Stand-ins for the connections of clients, and a lobby with rooms to connect
+//! them to. Not every test uses every helper.
+#![allow(dead_code)]
+
+use actix::prelude::*;
+use server::config::{Config, RoomConfig};
+use server::history::HistoryBackend;
+use server::lobby::Lobby;
+use server::messages::{
+    ClaimSeat, ClientActorMessage, Connect, Disconnect, DroppedOutputs, FindRoom, Join, WsMessage,
+};
+use server::proto::{Lifecycle, MessageOutput, Output, Role};
+use server::rooms::ChatRoom;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// The room every test starts out with.
+pub const ROOM: Uuid = Uuid::from_u128(1);
+
+/// A lobby with a single persistent room of two seats, where history is kept
+/// in memory and ephemeral rooms go away soon after they empty out.
+pub fn config() -> Config {
+    let mut config = Config {
+        room_threads: Some(1),
+        rooms: vec![room(ROOM, "Test room")],
+        ..Config::default()
+    };
+    config.limits.default_max_clients = 2;
+    config.timeouts.idle_room_timeout = Duration::from_millis(50);
+    config
+}
+
+/// A public, persistent room where everyone may post.
+pub fn room(id: Uuid, name: &str) -> RoomConfig {
+    RoomConfig {
+        id,
+        name: name.to_string(),
+        topic: None,
+        max_clients: None,
+        owners: Vec::new(),
+        moderators: Vec::new(),
+        members: Vec::new(),
+        guests: Vec::new(),
+        default_role: Role::Member,
+        lifecycle: Lifecycle::Persistent,
+        password_hash: None,
+        private: false,
+        invite_only: false,
+    }
+}
+
+pub fn start_lobby(config: &Config) -> Addr<Lobby> {
+    Lobby::new(HistoryBackend::Memory(Default::default()), config)
+        .unwrap()
+        .start()
+}
+
+/// Waits for the lobby to handle everything it was sent before, by asking it
+/// for a seat that nobody has.
+pub async fn settle_lobby(lobby: &Addr<Lobby>) {
+    let seat = lobby
+        .send(ClaimSeat {
+            token: Uuid::new_v4(),
+            self_id: None,
+        })
+        .await
+        .unwrap();
+    assert!(seat.is_none());
+}
+
+/// Waits for a room to handle everything it was sent before, by disconnecting
+/// a client that was never in it. A room that closed in the meantime is done
+/// as well.
+pub async fn settle_room(room: &Addr<ChatRoom>) {
+    let _ = room
+        .send(Disconnect {
+            addr: TestClient::new("nobody").recipient(),
+            self_id: Uuid::new_v4(),
+            resumable: false,
+        })
+        .await;
+}
+
+/// Finds a room that exists, without joining it.
+pub async fn find_room(lobby: &Addr<Lobby>, client: &TestClient, room_id: Uuid) -> Addr<ChatRoom> {
+    lobby
+        .send(FindRoom {
+            addr: client.recipient(),
+            room_id,
+            self_id: client.id,
+            username: client.name.clone(),
+            max_clients: None,
+        })
+        .await
+        .unwrap()
+        .expect("room not found")
+}
+
+/// Joins a room the way a connection does, returning the room and whether the
+/// client was let in (or into the queue).
+pub async fn try_join(
+    lobby: &Addr<Lobby>,
+    client: &TestClient,
+    room_id: Uuid,
+    join: Join,
+) -> (Addr<ChatRoom>, bool) {
+    let room = find_room(lobby, client, room_id).await;
+    let joined = room.send(join).await.unwrap();
+    (room, joined)
+}
+
+/// Takes a seat in a room, and forgets the outputs sent to the client on the
+/// way in.
+pub async fn join(lobby: &Addr<Lobby>, client: &TestClient, room_id: Uuid) -> Addr<ChatRoom> {
+    let (room, joined) = try_join(lobby, client, room_id, client.join()).await;
+    assert!(joined, "{} couldn't join", client.name);
+    assert!(matches!(
+        client.outputs().await.last(),
+        Some(Output::Joined(_))
+    ));
+    room
+}
+
+/// Leaves a room on purpose.
+pub async fn leave(room: &Addr<ChatRoom>, client: &TestClient) {
+    room.send(Disconnect {
+        addr: client.recipient(),
+        self_id: client.id,
+        resumable: false,
+    })
+    .await
+    .unwrap();
+}
+
+/// Posts a message in a room, returning it as the room sent it back to the
+/// client that posted it. Anything else the client was sent is forgotten.
+pub async fn post(room: &Addr<ChatRoom>, client: &TestClient, body: &str) -> MessageOutput {
+    room.send(ClientActorMessage {
+        id: client.id,
+        msg: body.to_string(),
+        nonce: None,
+    })
+    .await
+    .unwrap();
+    match client.outputs().await.as_slice() {
+        [.., Output::Posted(posted)] => posted.message.clone(),
+        outputs => panic!("expected the post, got {:?}", outputs),
+    }
+}
+
+/// Subscribes a client to the list of rooms, returning the rooms it is sent.
+pub async fn connect(lobby: &Addr<Lobby>, client: &TestClient) -> Vec<Uuid> {
+    lobby
+        .send(Connect {
+            addr: client.recipient(),
+        })
+        .await
+        .unwrap();
+    match client.outputs().await.as_slice() {
+        [Output::Rooms(rooms)] => rooms.rooms.iter().map(|room| room.id).collect(),
+        outputs => panic!("expected the rooms, got {:?}", outputs),
+    }
+}
+
+/// Plays a client's connection, recording the outputs sent to it.
+#[derive(Default)]
+pub struct Recorder {
+    outputs: Vec<Output>,
+    close_code: Option<u16>,
+}
+
+impl Actor for Recorder {
+    type Context = Context<Self>;
+}
+
+impl Handler<WsMessage> for Recorder {
+    type Result = ();
+
+    fn handle(&mut self, msg: WsMessage, _: &mut Context<Self>) {
+        let text = match msg {
+            WsMessage::Text(text) | WsMessage::Removed(_, text) => text,
+            WsMessage::Close(reason) => {
+                self.close_code = reason.map(|reason| reason.code.into());
+                return;
+            }
+        };
+        let output = serde_json::from_str(&text)
+            .unwrap_or_else(|e| panic!("couldn't parse {}: {}", text, e));
+        self.outputs.push(output);
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Vec<Output>")]
+struct TakeOutputs;
+
+impl Handler<TakeOutputs> for Recorder {
+    type Result = MessageResult<TakeOutputs>;
+
+    fn handle(&mut self, _: TakeOutputs, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(std::mem::take(&mut self.outputs))
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Option<u16>")]
+struct TakeCloseCode;
+
+impl Handler<TakeCloseCode> for Recorder {
+    type Result = Option<u16>;
+
+    fn handle(&mut self, _: TakeCloseCode, _: &mut Context<Self>) -> Option<u16> {
+        self.close_code.take()
+    }
+}
+
+/// A client, connected through a `Recorder`.
+pub struct TestClient {
+    pub id: Uuid,
+    pub name: String,
+    pub addr: Addr<Recorder>,
+    pub dropped: DroppedOutputs,
+}
+
+impl TestClient {
+    pub fn new(name: &str) -> Self {
+        Self::with_addr(name, Recorder::default().start())
+    }
+
+    /// A client whose connection doesn't take any outputs until it is
+    /// resumed by running the returned context, and has room for `capacity`
+    /// outputs in the meantime.
+    pub fn stalled(name: &str, capacity: usize) -> (Self, Context<Recorder>) {
+        let mut ctx = Context::new();
+        ctx.set_mailbox_capacity(capacity);
+        (Self::with_addr(name, ctx.address()), ctx)
+    }
+
+    fn with_addr(name: &str, addr: Addr<Recorder>) -> Self {
+        TestClient {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            addr,
+            dropped: DroppedOutputs::default(),
+        }
+    }
+
+    pub fn recipient(&self) -> Recipient<WsMessage> {
+        self.addr.clone().recipient()
+    }
+
+    /// The message a connection sends to take a seat in a room.
+    pub fn join(&self) -> Join {
+        Join {
+            addr: self.recipient(),
+            self_id: self.id,
+            username: self.name.clone(),
+            wait: false,
+            ip: None,
+            password: None,
+            invite: None,
+            dropped: DroppedOutputs::clone(&self.dropped),
+        }
+    }
+
+    /// Takes the outputs the client was sent so far.
+    pub async fn outputs(&self) -> Vec<Output> {
+        self.addr.send(TakeOutputs).await.unwrap()
+    }
+
+    /// Takes the close code the connection was closed with, if it was.
+    pub async fn close_code(&self) -> Option<u16> {
+        self.addr.send(TakeCloseCode).await.unwrap()
+    }
+}
@@ -0,0 +1,443 @@
+//! Drives the lobby the way connections do, and checks what every client is
+//! sent.
+
+mod common;
+
+use actix::Addr;
+use common::*;
+use server::lobby::Lobby;
+use server::messages::{
+    CreateRoom, DeleteRoom, Direct, DirectHistory, FindRoom, Join, Unsubscribe, UpdateRoom,
+};
+use server::proto::{
+    DirectMessageOutput, Lifecycle, Output, OutputError, Room, RoomAddedOutput, RoomDeletedOutput,
+    RoomRemovedOutput, RoomUpdatedOutput, UserOutput,
+};
+use uuid::Uuid;
+
+fn direct(from: &TestClient, to: Uuid, body: &str) -> Direct {
+    Direct {
+        id: from.id,
+        to,
+        body: body.to_string(),
+    }
+}
+
+#[actix::test]
+async fn direct_messages_reach_both_parties() {
+    let lobby = start_lobby(&config());
+    let alice = TestClient::new("alice");
+    let bob = TestClient::new("bob");
+    join(&lobby, &alice, ROOM).await;
+    join(&lobby, &bob, ROOM).await;
+    alice.outputs().await;
+    settle_lobby(&lobby).await;
+
+    lobby.send(direct(&alice, bob.id, "hi bob")).await.unwrap();
+    let outputs = bob.outputs().await;
+    assert_eq!(outputs, alice.outputs().await);
+    match outputs.as_slice() {
+        [Output::DirectMessage(DirectMessageOutput { to, message })] => {
+            assert_eq!(*to, UserOutput::new(bob.id, "bob"));
+            assert_eq!(message.user, UserOutput::new(alice.id, "alice"));
+            assert_eq!(message.body, "hi bob");
+        }
+        outputs => panic!("expected the direct message, got {:?}", outputs),
+    }
+}
+
+#[actix::test]
+async fn direct_messages_need_both_parties_connected() {
+    let lobby = start_lobby(&config());
+    let alice = TestClient::new("alice");
+    let bob = TestClient::new("bob");
+    let carol = TestClient::new("carol");
+    join(&lobby, &alice, ROOM).await;
+    join(&lobby, &bob, ROOM).await;
+    let wait = Join {
+        wait: true,
+        ..carol.join()
+    };
+    try_join(&lobby, &carol, ROOM, wait).await;
+    alice.outputs().await;
+    carol.outputs().await;
+    settle_lobby(&lobby).await;
+
+    lobby
+        .send(direct(&alice, Uuid::new_v4(), "anyone there?"))
+        .await
+        .unwrap();
+    assert_eq!(
+        alice.outputs().await,
+        [Output::Error(OutputError::UserNotConnected)]
+    );
+
+    // Clients still waiting for a seat can't send or receive direct messages.
+    lobby
+        .send(direct(&carol, alice.id, "let me in"))
+        .await
+        .unwrap();
+    assert_eq!(
+        carol.outputs().await,
+        [Output::Error(OutputError::NotJoined)]
+    );
+    lobby
+        .send(direct(&alice, carol.id, "hang on"))
+        .await
+        .unwrap();
+    assert_eq!(
+        alice.outputs().await,
+        [Output::Error(OutputError::UserNotConnected)]
+    );
+}
+
+#[actix::test]
+async fn direct_history_outlives_the_rooms() {
+    let lobby = start_lobby(&config());
+    let alice = TestClient::new("alice");
+    let bob = TestClient::new("bob");
+    let room = join(&lobby, &alice, ROOM).await;
+    join(&lobby, &bob, ROOM).await;
+    settle_lobby(&lobby).await;
+    lobby.send(direct(&alice, bob.id, "hi bob")).await.unwrap();
+    lobby
+        .send(direct(&bob, alice.id, "hi alice"))
+        .await
+        .unwrap();
+    leave(&room, &alice).await;
+    leave(&room, &bob).await;
+    settle_lobby(&lobby).await;
+    alice.outputs().await;
+
+    lobby
+        .send(DirectHistory {
+            addr: alice.recipient(),
+            id: alice.id,
+            with: bob.id,
+            before: None,
+            after: None,
+            limit: Some(1),
+        })
+        .await
+        .unwrap();
+    match alice.outputs().await.as_slice() {
+        [Output::History(history)] => {
+            assert_eq!(history.with, Some(bob.id));
+            let bodies: Vec<_> = history.messages.iter().map(|m| &m.body).collect();
+            assert_eq!(bodies, ["hi alice"]);
+            assert!(history.cursor.is_some());
+        }
+        outputs => panic!("expected the history, got {:?}", outputs),
+    }
+
+    // Nobody else's conversations can be read.
+    let carol = TestClient::new("carol");
+    lobby
+        .send(DirectHistory {
+            addr: carol.recipient(),
+            id: carol.id,
+            with: bob.id,
+            before: None,
+            after: None,
+            limit: None,
+        })
+        .await
+        .unwrap();
+    match carol.outputs().await.as_slice() {
+        [Output::History(history)] => assert_eq!(history.messages, []),
+        outputs => panic!("expected the history, got {:?}", outputs),
+    }
+}
+
+fn create_room(owner: &TestClient, name: &str, lifecycle: Lifecycle) -> CreateRoom {
+    CreateRoom {
+        addr: owner.recipient(),
+        id: owner.id,
+        name: name.to_string(),
+        topic: None,
+        max_clients: None,
+        lifecycle,
+        password_hash: None,
+        private: false,
+        invite_only: false,
+    }
+}
+
+fn update_room(owner: &TestClient, room_id: Uuid) -> UpdateRoom {
+    UpdateRoom {
+        addr: owner.recipient(),
+        id: owner.id,
+        room_id,
+        name: None,
+        topic: None,
+        max_clients: None,
+        password_hash: None,
+        private: None,
+        invite_only: None,
+    }
+}
+
+fn delete_room(owner: &TestClient, room_id: Uuid) -> DeleteRoom {
+    DeleteRoom {
+        addr: owner.recipient(),
+        id: owner.id,
+        room_id,
+    }
+}
+
+/// Creates a room, returning it as it was sent back to its owner.
+async fn create(lobby: &Addr<Lobby>, owner: &TestClient, msg: CreateRoom) -> Room {
+    lobby.send(msg).await.unwrap();
+    match owner.outputs().await.as_slice() {
+        [Output::RoomCreated(created)] => created.room.clone(),
+        outputs => panic!("expected the room, got {:?}", outputs),
+    }
+}
+
+#[actix::test]
+async fn owners_manage_the_rooms_they_create() {
+    let mut config = config();
+    config.create_rooms_on_join = false;
+    let lobby = start_lobby(&config);
+    let alice = TestClient::new("alice");
+    let bob = TestClient::new("bob");
+
+    let room = create(
+        &lobby,
+        &alice,
+        create_room(&alice, "Den", Lifecycle::Ephemeral),
+    )
+    .await;
+    assert_eq!(room.name, "Den");
+    assert_eq!(room.connected_clients, 0);
+    let addr = join(&lobby, &bob, room.id).await;
+
+    // Only owners can change or delete a room, and they don't have to be in
+    // it.
+    lobby.send(update_room(&bob, room.id)).await.unwrap();
+    lobby.send(delete_room(&bob, room.id)).await.unwrap();
+    settle_room(&addr).await;
+    assert_eq!(
+        bob.outputs().await,
+        vec![Output::Error(OutputError::Forbidden); 2]
+    );
+
+    let update = UpdateRoom {
+        name: Some("Study".to_string()),
+        topic: Some("Quiet please".to_string()),
+        ..update_room(&alice, room.id)
+    };
+    lobby.send(update).await.unwrap();
+    settle_room(&addr).await;
+    let updated = Room {
+        name: "Study".to_string(),
+        topic: Some("Quiet please".to_string()),
+        connected_clients: 1,
+        ..room.clone()
+    };
+    for client in [&alice, &bob] {
+        assert_eq!(
+            client.outputs().await,
+            [Output::RoomUpdated(RoomUpdatedOutput::new(updated.clone()))]
+        );
+    }
+
+    lobby.send(delete_room(&alice, room.id)).await.unwrap();
+    settle_room(&addr).await;
+    for client in [&alice, &bob] {
+        assert_eq!(
+            client.outputs().await,
+            [Output::RoomDeleted(RoomDeletedOutput::new(room.id))]
+        );
+    }
+
+    // The room is gone for good.
+    settle_lobby(&lobby).await;
+    let found = lobby
+        .send(FindRoom {
+            addr: bob.recipient(),
+            room_id: room.id,
+            self_id: bob.id,
+            username: bob.name.clone(),
+            max_clients: None,
+        })
+        .await
+        .unwrap();
+    assert!(found.is_none());
+    lobby.send(update_room(&alice, room.id)).await.unwrap();
+    assert_eq!(
+        alice.outputs().await,
+        [Output::Error(OutputError::RoomNotFound)]
+    );
+}
+
+#[actix::test]
+async fn clients_only_create_a_few_persistent_rooms() {
+    let mut config = config();
+    config.limits.max_persistent_rooms = 1;
+    let lobby = start_lobby(&config);
+    let alice = TestClient::new("alice");
+
+    create(
+        &lobby,
+        &alice,
+        create_room(&alice, "Den", Lifecycle::Persistent),
+    )
+    .await;
+    lobby
+        .send(create_room(&alice, "Study", Lifecycle::Persistent))
+        .await
+        .unwrap();
+    assert_eq!(
+        alice.outputs().await,
+        [Output::Error(OutputError::TooManyRooms)]
+    );
+
+    // Ephemeral rooms go away by themselves, so there is no limit on them.
+    create(
+        &lobby,
+        &alice,
+        create_room(&alice, "Study", Lifecycle::Ephemeral),
+    )
+    .await;
+}
+
+#[actix::test]
+async fn subscribers_hear_about_changes_to_public_rooms() {
+    let lobby = start_lobby(&config());
+    let watcher = TestClient::new("watcher");
+    let alice = TestClient::new("alice");
+    assert_eq!(connect(&lobby, &watcher).await, [ROOM]);
+
+    let room = create(
+        &lobby,
+        &alice,
+        create_room(&alice, "Den", Lifecycle::Ephemeral),
+    )
+    .await;
+    let hidden = CreateRoom {
+        private: true,
+        ..create_room(&alice, "Hideout", Lifecycle::Ephemeral)
+    };
+    let hidden = create(&lobby, &alice, hidden).await;
+    assert_eq!(
+        watcher.outputs().await,
+        [Output::RoomAdded(RoomAddedOutput::new(room.clone()))]
+    );
+
+    // Every seat taken or given up shows in the list.
+    let addr = join(&lobby, &alice, room.id).await;
+    join(&lobby, &alice, hidden.id).await;
+    settle_lobby(&lobby).await;
+    let occupied = Room {
+        connected_clients: 1,
+        ..room.clone()
+    };
+    assert_eq!(
+        watcher.outputs().await,
+        [Output::RoomUpdated(RoomUpdatedOutput::new(
+            occupied.clone()
+        ))]
+    );
+
+    // A room made private leaves the list, and comes back once it is public
+    // again.
+    for private in [true, false] {
+        let update = UpdateRoom {
+            private: Some(private),
+            ..update_room(&alice, room.id)
+        };
+        lobby.send(update).await.unwrap();
+        settle_room(&addr).await;
+    }
+    settle_lobby(&lobby).await;
+    assert_eq!(
+        watcher.outputs().await,
+        [
+            Output::RoomRemoved(RoomRemovedOutput::new(room.id)),
+            Output::RoomAdded(RoomAddedOutput::new(occupied)),
+        ]
+    );
+
+    for room_id in [hidden.id, room.id] {
+        lobby.send(delete_room(&alice, room_id)).await.unwrap();
+    }
+    settle_room(&addr).await;
+    settle_lobby(&lobby).await;
+    assert_eq!(
+        watcher.outputs().await,
+        [Output::RoomRemoved(RoomRemovedOutput::new(room.id))]
+    );
+
+    // Clients that went away aren't told anything anymore.
+    lobby
+        .send(Unsubscribe {
+            addr: watcher.recipient(),
+        })
+        .await
+        .unwrap();
+    alice.outputs().await;
+    create(
+        &lobby,
+        &alice,
+        create_room(&alice, "Den", Lifecycle::Ephemeral),
+    )
+    .await;
+    assert_eq!(watcher.outputs().await, []);
+}
+
+#[actix::test]
+async fn empty_ephemeral_rooms_are_removed() {
+    let mut config = config();
+    config.create_rooms_on_join = false;
+    let idle_room_timeout = config.timeouts.idle_room_timeout;
+    let lobby = start_lobby(&config);
+    let watcher = TestClient::new("watcher");
+    let alice = TestClient::new("alice");
+    connect(&lobby, &watcher).await;
+
+    let empty = create(
+        &lobby,
+        &alice,
+        create_room(&alice, "Den", Lifecycle::Ephemeral),
+    )
+    .await;
+    let occupied = create(
+        &lobby,
+        &alice,
+        create_room(&alice, "Study", Lifecycle::Ephemeral),
+    )
+    .await;
+    let persistent = create(
+        &lobby,
+        &alice,
+        create_room(&alice, "Hall", Lifecycle::Persistent),
+    )
+    .await;
+    let room = join(&lobby, &alice, occupied.id).await;
+    watcher.outputs().await;
+
+    // Rooms that someone is in, and rooms that are meant to stay, are kept.
+    actix::clock::sleep(idle_room_timeout * 4).await;
+    settle_lobby(&lobby).await;
+    assert_eq!(
+        watcher.outputs().await,
+        [Output::RoomRemoved(RoomRemovedOutput::new(empty.id))]
+    );
+    for room_id in [occupied.id, persistent.id] {
+        find_room(&lobby, &alice, room_id).await;
+    }
+
+    // A room that empties out is removed once it has been empty for long
+    // enough.
+    leave(&room, &alice).await;
+    actix::clock::sleep(idle_room_timeout * 4).await;
+    settle_lobby(&lobby).await;
+    assert_eq!(
+        watcher.outputs().await,
+        [
+            Output::RoomUpdated(RoomUpdatedOutput::new(occupied.clone())),
+            Output::RoomRemoved(RoomRemovedOutput::new(occupied.id)),
+        ]
+    );
+}
@@ -0,0 +1,446 @@
+//! Drives rooms the way connections do, and checks what every client is sent.
+
+mod common;
+
+use common::*;
+use server::messages::TOO_SLOW_CLOSE_CODE;
+use server::messages::{
+    ClientActorMessage, Delete, Edit, History, Join, Moderate, ModerationAction, Typing,
+};
+use server::proto::{
+    MessageDeletedOutput, MessageEditedOutput, MessageOutput, ModeratedOutput, Moderation, Output,
+    OutputError, QueuedOutput, TypingOutput, TypingStatus, UserJoinedOutput, UserLeftOutput,
+    UserOutput, UserPostedOutput,
+};
+use std::sync::atomic::Ordering;
+
+#[actix::test]
+async fn full_rooms_turn_clients_away() {
+    let lobby = start_lobby(&config());
+    let alice = TestClient::new("alice");
+    let bob = TestClient::new("bob");
+    let carol = TestClient::new("carol");
+    join(&lobby, &alice, ROOM).await;
+    join(&lobby, &bob, ROOM).await;
+
+    let (_, joined) = try_join(&lobby, &carol, ROOM, carol.join()).await;
+    assert!(!joined);
+    assert_eq!(
+        carol.outputs().await,
+        [Output::Error(OutputError::RoomFull)]
+    );
+}
+
+#[actix::test]
+async fn waiting_clients_are_seated_in_order() {
+    let lobby = start_lobby(&config());
+    let alice = TestClient::new("alice");
+    let bob = TestClient::new("bob");
+    let carol = TestClient::new("carol");
+    let dave = TestClient::new("dave");
+    let room = join(&lobby, &alice, ROOM).await;
+    join(&lobby, &bob, ROOM).await;
+    alice.outputs().await;
+
+    for (client, position) in [(&carol, 1), (&dave, 2)] {
+        let wait = Join {
+            wait: true,
+            ..client.join()
+        };
+        let (_, joined) = try_join(&lobby, client, ROOM, wait).await;
+        assert!(joined);
+        assert_eq!(
+            client.outputs().await,
+            [Output::Queued(QueuedOutput {
+                room: ROOM,
+                position
+            })]
+        );
+    }
+
+    // Nobody in the room hears about clients in line.
+    assert_eq!(alice.outputs().await, []);
+
+    leave(&room, &alice).await;
+    assert_eq!(
+        bob.outputs().await,
+        [
+            Output::UserLeft(UserLeftOutput::new(alice.id, "alice")),
+            Output::UserJoined(UserJoinedOutput::new(UserOutput::new(carol.id, "carol"))),
+        ]
+    );
+    match carol.outputs().await.as_slice() {
+        [Output::Joined(joined)] => {
+            assert_eq!(joined.user, UserOutput::new(carol.id, "carol"));
+            assert_eq!(joined.others.len(), 2);
+        }
+        outputs => panic!("expected carol to be seated, got {:?}", outputs),
+    }
+    assert_eq!(
+        dave.outputs().await,
+        [Output::Queued(QueuedOutput {
+            room: ROOM,
+            position: 1
+        })]
+    );
+
+    // Leaving the queue gives up the place in line.
+    leave(&room, &dave).await;
+    leave(&room, &bob).await;
+    assert_eq!(dave.outputs().await, []);
+}
+
+#[actix::test]
+async fn only_authors_edit_their_messages() {
+    let lobby = start_lobby(&config());
+    let alice = TestClient::new("alice");
+    let bob = TestClient::new("bob");
+    let room = join(&lobby, &alice, ROOM).await;
+    join(&lobby, &bob, ROOM).await;
+    let message = post(&room, &alice, "helo").await;
+    bob.outputs().await;
+
+    room.send(Edit {
+        id: bob.id,
+        message_id: message.id,
+        body: "hello".to_string(),
+    })
+    .await
+    .unwrap();
+    assert_eq!(bob.outputs().await, [Output::Error(OutputError::Forbidden)]);
+    assert_eq!(alice.outputs().await, []);
+
+    room.send(Edit {
+        id: alice.id,
+        message_id: message.id,
+        body: "hello".to_string(),
+    })
+    .await
+    .unwrap();
+    let outputs = alice.outputs().await;
+    assert_eq!(outputs, bob.outputs().await);
+    match outputs.as_slice() {
+        [Output::MessageEdited(MessageEditedOutput { message: edited })] => {
+            assert_eq!(edited.id, message.id);
+            assert_eq!(edited.body, "hello");
+            assert_eq!(edited.created_at, message.created_at);
+            assert!(edited.edited_at.is_some());
+        }
+        outputs => panic!("expected the edit, got {:?}", outputs),
+    }
+}
+
+#[actix::test]
+async fn deleted_messages_are_kept_as_tombstones() {
+    let alice = TestClient::new("alice");
+    let bob = TestClient::new("bob");
+    let carol = TestClient::new("carol");
+    let mut config = config();
+    config.rooms[0].max_clients = Some(3);
+    config.rooms[0].moderators.push(carol.id);
+    let lobby = start_lobby(&config);
+    let room = join(&lobby, &alice, ROOM).await;
+    join(&lobby, &bob, ROOM).await;
+    join(&lobby, &carol, ROOM).await;
+    let message = post(&room, &alice, "hello").await;
+    bob.outputs().await;
+    carol.outputs().await;
+
+    // Members can only delete their own messages, moderators anyone's.
+    let delete = |client: &TestClient| Delete {
+        id: client.id,
+        message_id: message.id,
+    };
+    room.send(delete(&bob)).await.unwrap();
+    assert_eq!(bob.outputs().await, [Output::Error(OutputError::Forbidden)]);
+
+    room.send(delete(&carol)).await.unwrap();
+    let tombstone = MessageOutput {
+        body: String::new(),
+        deleted: true,
+        ..message.clone()
+    };
+    for client in [&alice, &bob, &carol] {
+        assert_eq!(
+            client.outputs().await,
+            [Output::MessageDeleted(MessageDeletedOutput::new(
+                tombstone.clone()
+            ))]
+        );
+    }
+
+    // A deleted message can't be changed anymore, but stays in the history.
+    room.send(Edit {
+        id: alice.id,
+        message_id: message.id,
+        body: "hello again".to_string(),
+    })
+    .await
+    .unwrap();
+    room.send(delete(&alice)).await.unwrap();
+    room.send(History {
+        id: alice.id,
+        before: None,
+        after: None,
+        limit: None,
+    })
+    .await
+    .unwrap();
+    match alice.outputs().await.as_slice() {
+        [Output::Error(OutputError::MessageNotFound), Output::Error(OutputError::MessageNotFound), Output::History(history)] =>
+        {
+            assert_eq!(history.messages, [tombstone]);
+        }
+        outputs => panic!("expected the tombstone, got {:?}", outputs),
+    }
+}
+
+#[actix::test]
+async fn clients_without_a_seat_are_told_they_have_not_joined() {
+    let lobby = start_lobby(&config());
+    let alice = TestClient::new("alice");
+    let bob = TestClient::new("bob");
+    let carol = TestClient::new("carol");
+    let room = join(&lobby, &alice, ROOM).await;
+    join(&lobby, &bob, ROOM).await;
+    let wait = Join {
+        wait: true,
+        ..carol.join()
+    };
+    try_join(&lobby, &carol, ROOM, wait).await;
+    let message = post(&room, &alice, "hello").await;
+    bob.outputs().await;
+    carol.outputs().await;
+
+    room.send(Typing {
+        id: carol.id,
+        status: TypingStatus::Started,
+    })
+    .await
+    .unwrap();
+    room.send(ClientActorMessage {
+        id: carol.id,
+        msg: "let me in".to_string(),
+        nonce: None,
+    })
+    .await
+    .unwrap();
+    room.send(History {
+        id: carol.id,
+        before: None,
+        after: None,
+        limit: None,
+    })
+    .await
+    .unwrap();
+    room.send(Delete {
+        id: carol.id,
+        message_id: message.id,
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        carol.outputs().await,
+        vec![Output::Error(OutputError::NotJoined); 4]
+    );
+
+    // Clients that never joined have nowhere to be told, and the room carries
+    // on without them.
+    let stranger = TestClient::new("stranger");
+    room.send(Typing {
+        id: stranger.id,
+        status: TypingStatus::Started,
+    })
+    .await
+    .unwrap();
+    assert_eq!(stranger.outputs().await, []);
+    assert_eq!(alice.outputs().await, []);
+    assert_eq!(bob.outputs().await, []);
+    post(&room, &alice, "still here").await;
+}
+
+#[actix::test]
+async fn clients_that_can_not_keep_up_are_disconnected() {
+    let lobby = start_lobby(&config());
+    let alice = TestClient::new("alice");
+    let (bob, stalled) = TestClient::stalled("bob", 1);
+    let room = join(&lobby, &alice, ROOM).await;
+    let (_, joined) = try_join(&lobby, &bob, ROOM, bob.join()).await;
+    assert!(joined);
+    alice.outputs().await;
+
+    // Typing events are left out for a client whose queue is full, and
+    // counted.
+    for _ in 0..4 {
+        for status in [TypingStatus::Started, TypingStatus::Stopped] {
+            room.send(Typing {
+                id: alice.id,
+                status,
+            })
+            .await
+            .unwrap();
+        }
+    }
+    let dropped = bob.dropped.load(Ordering::Relaxed);
+    assert!(dropped > 0);
+    assert_eq!(alice.outputs().await, []);
+
+    // Posts can't be left out, so the client is disconnected instead.
+    room.send(ClientActorMessage {
+        id: alice.id,
+        msg: "hello".to_string(),
+        nonce: None,
+    })
+    .await
+    .unwrap();
+    settle_lobby(&lobby).await;
+    settle_room(&room).await;
+    match alice.outputs().await.as_slice() {
+        [Output::Posted(_), Output::UserLeft(left)] => assert_eq!(left.user.id, bob.id),
+        outputs => panic!("expected bob to be disconnected, got {:?}", outputs),
+    }
+    assert_eq!(bob.dropped.load(Ordering::Relaxed), dropped);
+
+    // Once it catches up, the client finds its connection closed without the
+    // post.
+    stalled.run(Recorder::default());
+    assert_eq!(bob.close_code().await, Some(TOO_SLOW_CLOSE_CODE));
+    let outputs = bob.outputs().await;
+    assert!(matches!(outputs.first(), Some(Output::Joined(_))));
+    assert!(outputs[1..]
+        .iter()
+        .all(|output| matches!(output, Output::Typing(TypingOutput { .. }))));
+}
+
+#[actix::test]
+async fn retried_posts_are_only_posted_once() {
+    let lobby = start_lobby(&config());
+    let alice = TestClient::new("alice");
+    let bob = TestClient::new("bob");
+    let room = join(&lobby, &alice, ROOM).await;
+    join(&lobby, &bob, ROOM).await;
+    alice.outputs().await;
+
+    let post = |client: &TestClient, body: &str, nonce: &str| ClientActorMessage {
+        id: client.id,
+        msg: body.to_string(),
+        nonce: Some(nonce.to_string()),
+    };
+    let posted = |outputs: Vec<Output>| match outputs.as_slice() {
+        [Output::Posted(posted)] => posted.clone(),
+        outputs => panic!("expected the post, got {:?}", outputs),
+    };
+
+    room.send(post(&alice, "hello", "1")).await.unwrap();
+    let first = posted(alice.outputs().await);
+    assert_eq!(first.nonce.as_deref(), Some("1"));
+    assert_eq!(
+        bob.outputs().await,
+        [Output::UserPosted(UserPostedOutput::new(
+            first.message.clone()
+        ))]
+    );
+
+    // The retry gets the message that was posted the first time, even if the
+    // body got mangled on the way, and nobody else sees it twice.
+    room.send(post(&alice, "hallo", "1")).await.unwrap();
+    assert_eq!(posted(alice.outputs().await), first);
+    assert_eq!(bob.outputs().await, []);
+
+    // Nonces only have to be unique per client.
+    room.send(post(&bob, "hello", "1")).await.unwrap();
+    assert_ne!(posted(bob.outputs().await).message.id, first.message.id);
+    alice.outputs().await;
+    room.send(post(&alice, "hello", "2")).await.unwrap();
+    let second = posted(alice.outputs().await);
+    assert_ne!(second.message.id, first.message.id);
+    assert_eq!(second.nonce.as_deref(), Some("2"));
+}
+
+#[actix::test]
+async fn moderators_kick_and_ban_users() {
+    let alice = TestClient::new("alice");
+    let bob = TestClient::new("bob");
+    let carol = TestClient::new("carol");
+    let mut config = config();
+    config.rooms[0].max_clients = Some(3);
+    config.rooms[0].owners.push(carol.id);
+    let lobby = start_lobby(&config);
+    let room = join(&lobby, &alice, ROOM).await;
+    join(&lobby, &bob, ROOM).await;
+    join(&lobby, &carol, ROOM).await;
+    alice.outputs().await;
+    bob.outputs().await;
+
+    let moderate = |by: &TestClient, action| Moderate {
+        id: by.id,
+        user: alice.id,
+        action,
+    };
+    let kick = || ModerationAction::Kick {
+        reason: Some("spam".to_string()),
+    };
+    let moderated = |action, reason: Option<&str>| {
+        Output::Moderated(ModeratedOutput::new(
+            action,
+            alice.id,
+            UserOutput::new(carol.id, "carol"),
+            reason.map(String::from),
+            None,
+        ))
+    };
+    let left = Output::UserLeft(UserLeftOutput::new(alice.id, "alice"));
+
+    // Only moderators can kick, and only users below them.
+    room.send(moderate(&bob, kick())).await.unwrap();
+    assert_eq!(bob.outputs().await, [Output::Error(OutputError::Forbidden)]);
+    room.send(Moderate {
+        user: carol.id,
+        ..moderate(&bob, kick())
+    })
+    .await
+    .unwrap();
+    assert_eq!(bob.outputs().await, [Output::Error(OutputError::Forbidden)]);
+
+    room.send(moderate(&carol, kick())).await.unwrap();
+    let kicked = moderated(Moderation::Kicked, Some("spam"));
+    assert_eq!(bob.outputs().await, [left.clone(), kicked.clone()]);
+    assert_eq!(carol.outputs().await, [left.clone(), kicked.clone()]);
+    assert_eq!(alice.outputs().await, [kicked]);
+
+    // A kicked user can come back, a banned one can't.
+    join(&lobby, &alice, ROOM).await;
+    bob.outputs().await;
+    carol.outputs().await;
+    let ban = ModerationAction::Ban {
+        reason: None,
+        duration: None,
+        ip: false,
+    };
+    room.send(moderate(&carol, ban)).await.unwrap();
+    let banned = moderated(Moderation::Banned, None);
+    assert_eq!(bob.outputs().await, [left, banned.clone()]);
+    assert_eq!(alice.outputs().await, [banned]);
+
+    let (_, joined) = try_join(&lobby, &alice, ROOM, alice.join()).await;
+    assert!(!joined);
+    assert_eq!(alice.outputs().await, [Output::Error(OutputError::Banned)]);
+
+    room.send(moderate(&carol, ModerationAction::Unban))
+        .await
+        .unwrap();
+    assert_eq!(bob.outputs().await, [moderated(Moderation::Unbanned, None)]);
+    join(&lobby, &alice, ROOM).await;
+    carol.outputs().await;
+
+    // Users who aren't around can't be kicked.
+    leave(&room, &alice).await;
+    room.send(moderate(&carol, kick())).await.unwrap();
+    assert_eq!(
+        carol.outputs().await,
+        [
+            Output::UserLeft(UserLeftOutput::new(alice.id, "alice")),
+            Output::Error(OutputError::UserNotConnected),
+        ]
+    );
+}